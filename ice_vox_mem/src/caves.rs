@@ -0,0 +1,199 @@
+// Caves carved with 3D noise.
+//
+// Cheese caves are the big open chambers where a noise field rises above a threshold. Worm
+// caves are the long tunnels along the lines where two independent noise fields are both
+// close to zero. All noise is seeded from `CaveParams::seed`, so the same parameters always
+// carve the same caves.
+//
+// Like the terrain, every field has a Lipschitz bound, which decides whole octants as
+// untouched or carved out from a single sample at their center.
+
+use glam::*;
+
+use crate::noise::{Fbm, FbmParams};
+use crate::octree::{carved, is_leaf_octant, OctantFillState, VoxelOctree, DEFAULT_COLOR};
+use crate::terrain::{Terrain, TerrainParams};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaveParams {
+    pub seed: u64,
+    /// Frequency of the cheese cave noise, in cycles per world unit.
+    pub cheese_frequency: f32,
+    pub cheese_octaves: u32,
+    /// Cheese caves are carved where their noise is above this. Lower values give more and
+    /// bigger chambers, 1 or more turns them off.
+    pub cheese_threshold: f32,
+    /// Frequency of the worm cave noise, in cycles per world unit.
+    pub worm_frequency: f32,
+    pub worm_octaves: u32,
+    /// Worm caves are carved where both of their noise fields are within this of 0. Higher
+    /// values give wider tunnels, 0 turns them off.
+    pub worm_width: f32,
+    /// Vertical stretch of the caves, below 1 makes them flatter.
+    pub vertical_scale: f32,
+    /// Nothing above this height is carved, for example to keep caves below the surface.
+    pub max_height: Option<f32>,
+}
+
+impl Default for CaveParams {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            cheese_frequency: 1.0 / 48.0,
+            cheese_octaves: 3,
+            cheese_threshold: 0.35,
+            worm_frequency: 1.0 / 96.0,
+            worm_octaves: 2,
+            worm_width: 0.04,
+            vertical_scale: 0.5,
+            max_height: None,
+        }
+    }
+}
+
+//Noise field with its Lipschitz bound, in world units
+struct Field {
+    fbm: Fbm,
+    lipschitz: f32,
+}
+
+impl Field {
+    fn new(seed: u64, frequency: f32, octaves: u32) -> Self {
+        let fbm = Fbm::new(FbmParams {
+            seed,
+            octaves,
+            frequency,
+            ..FbmParams::default()
+        });
+        let lipschitz = fbm.lipschitz3();
+        Self { fbm, lipschitz }
+    }
+}
+
+//Which part of an octant a cave covers
+#[derive(Clone, Copy, PartialEq)]
+enum Coverage {
+    None,
+    Partial,
+    All,
+}
+
+pub struct Caves {
+    pub params: CaveParams,
+    cheese: Field,
+    worm_a: Field,
+    worm_b: Field,
+    //Extra factor on the Lipschitz bounds from squashing the y axis
+    stretch: f32,
+}
+
+impl Caves {
+    pub fn new(params: CaveParams) -> Self {
+        //Every field gets its own seed, derived from the one in the params
+        let seed = params.seed.wrapping_mul(3);
+        Self {
+            params,
+            cheese: Field::new(seed, params.cheese_frequency, params.cheese_octaves),
+            worm_a: Field::new(seed.wrapping_add(1), params.worm_frequency, params.worm_octaves),
+            worm_b: Field::new(seed.wrapping_add(2), params.worm_frequency, params.worm_octaves),
+            stretch: (1.0 / params.vertical_scale).max(1.0),
+        }
+    }
+
+    fn sample_point(&self, pos: Vec3) -> Vec3 {
+        vec3(pos.x, pos.y / self.params.vertical_scale, pos.z)
+    }
+
+    /// True if `pos` is inside a cave.
+    pub fn is_cave(&self, pos: Vec3) -> bool {
+        if let Some(max) = self.params.max_height {
+            if pos.y > max {
+                return false;
+            }
+        }
+        let p = self.sample_point(pos);
+        let w = self.params.worm_width;
+        self.cheese.fbm.sample3(p) > self.params.cheese_threshold
+            || (self.worm_a.fbm.sample3(p).abs() < w && self.worm_b.fbm.sample3(p).abs() < w)
+    }
+
+    //Coverage of an octant from the sample at its center, `radius` away from its corners
+    fn coverage(&self, center: Vec3, radius: f32) -> Coverage {
+        let p = self.sample_point(center);
+        let reach = |field: &Field| field.lipschitz * self.stretch * radius;
+
+        let cheese = self.cheese.fbm.sample3(p);
+        let threshold = self.params.cheese_threshold;
+        let cheese = if cheese - reach(&self.cheese) > threshold {
+            Coverage::All
+        } else if cheese + reach(&self.cheese) <= threshold {
+            Coverage::None
+        } else {
+            Coverage::Partial
+        };
+
+        let w = self.params.worm_width;
+        let (a, b) = (self.worm_a.fbm.sample3(p).abs(), self.worm_b.fbm.sample3(p).abs());
+        let (reach_a, reach_b) = (reach(&self.worm_a), reach(&self.worm_b));
+        let worm = if a - reach_a >= w || b - reach_b >= w {
+            Coverage::None
+        } else if a + reach_a < w && b + reach_b < w {
+            Coverage::All
+        } else {
+            Coverage::Partial
+        };
+
+        match (cheese, worm) {
+            (Coverage::All, _) | (_, Coverage::All) => Coverage::All,
+            (Coverage::None, Coverage::None) => Coverage::None,
+            _ => Coverage::Partial,
+        }
+    }
+
+    /// Classifier for `VoxelOctree::carve`, filled where the caves are. `leaf_size` is the
+    /// voxel size at max depth.
+    pub fn classifier(&self, leaf_size: Vec3) -> impl Fn(Vec3, Vec3, Vec3) -> OctantFillState + Copy + '_ {
+        move |center, inner, outer| {
+            if is_leaf_octant(inner, outer, leaf_size) {
+                return if self.is_cave(center) { OctantFillState::Full(DEFAULT_COLOR) } else { OctantFillState::Empty };
+            }
+
+            let half_size = (outer - inner).abs() * 0.5;
+            if let Some(max) = self.params.max_height {
+                if center.y - half_size.y > max {
+                    return OctantFillState::Empty;
+                }
+            }
+            let coverage = self.coverage(center, half_size.length());
+            let below_max = match self.params.max_height {
+                Some(max) => center.y + half_size.y <= max,
+                None => true,
+            };
+            match coverage {
+                Coverage::None => OctantFillState::Empty,
+                Coverage::All if below_max => OctantFillState::Full(DEFAULT_COLOR),
+                _ => OctantFillState::ContainsVoxel(DEFAULT_COLOR),
+            }
+        }
+    }
+}
+
+impl VoxelOctree {
+    /// Carves caves out of whatever is already in the tree, down to voxels of `max_depth`.
+    pub fn carve_caves(&mut self, params: CaveParams, max_depth: u8) -> usize {
+        let caves = Caves::new(params);
+        let leaf_size = self.leaf_size(max_depth);
+        self.carve(max_depth, caves.classifier(leaf_size))
+    }
+
+    /// Generates noise terrain with caves in it in a single pass, which is faster than
+    /// carving them out afterwards.
+    pub fn generate_terrain_with_caves(&mut self, terrain: TerrainParams, caves: CaveParams, max_depth: u8) -> usize {
+        let terrain = Terrain::new(terrain);
+        let caves = Caves::new(caves);
+        let leaf_size = self.leaf_size(max_depth);
+        let nodes_generated = self.generate(max_depth, carved(terrain.classifier(leaf_size), caves.classifier(leaf_size)));
+        trace!("Terrain nodes generated: {}", nodes_generated);
+        nodes_generated
+    }
+}
@@ -0,0 +1,476 @@
+// Boolean operations between two octrees.
+//
+// The result always lives on the lattice of the target tree. The target is walked top
+// down with a cursor into the other tree, pointing at the smallest node of the other tree
+// that contains the current target node. Where the other tree has a node with the same
+// bounds, whole subtrees are combined directly. Where its nodes don't line up with the
+// target's, because the roots are offset or scaled differently, the target gets subdivided
+// until it reaches the size of the finest leaves of the other tree, and those last nodes
+// are decided by sampling the other tree at their center.
+
+use glam::*;
+
+use crate::octree::{Color, NodeBounds, Octant, VoxelOctree};
+
+//Deepest the target gets subdivided to follow a misaligned tree
+const MAX_DEPTH: u8 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorPriority {
+    /// Where both trees are solid, keep the colour of the tree being modified.
+    Target,
+    /// Where both trees are solid, use the colour of the other tree.
+    Other,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Union,
+    Intersection,
+    Difference,
+}
+
+//What the other tree looks like inside a node of the target
+enum Coverage<'a> {
+    Empty,
+    Full(Color),
+    //The other tree has a node with exactly the same bounds
+    Same(&'a Octant),
+    //Partially filled, but the nodes don't line up
+    Mixed,
+}
+
+//Smallest node of the other tree that contains the current target node, if there is one
+type Cursor<'a> = Option<(&'a Octant, NodeBounds)>;
+
+//Tolerance for comparing bounds, relative to the size of the smaller node
+fn epsilon(bounds: NodeBounds) -> f32 {
+    bounds.half_size.min_element() * 1e-3
+}
+
+fn contains(outer: NodeBounds, inner: NodeBounds) -> bool {
+    let eps = epsilon(inner);
+    let (outer_min, outer_max, inner_min, inner_max) = (outer.min(), outer.max(), inner.min(), inner.max());
+    (0..3).all(|i| outer_min[i] - eps <= inner_min[i] && inner_max[i] <= outer_max[i] + eps)
+}
+
+fn overlaps(a: NodeBounds, b: NodeBounds) -> bool {
+    let eps = epsilon(a).min(epsilon(b));
+    let (a_min, a_max, b_min, b_max) = (a.min(), a.max(), b.min(), b.max());
+    (0..3).all(|i| a_min[i] + eps < b_max[i] && b_min[i] + eps < a_max[i])
+}
+
+fn is_empty(octant: &Octant) -> bool {
+    !octant.is_leaf() && octant.child_mask() == 0
+}
+
+fn recolor(octant: &mut Octant, (r, g, b): Color) {
+    if octant.is_leaf() {
+        *octant = Octant::leaf(r,g,b);
+    }
+    for i in 0..8 {
+        if let Some(child) = octant.child_mut(i) {
+            recolor(child, (r, g, b));
+        }
+    }
+}
+
+//Fills everything that's empty with `color`, leaving the solid parts as they are
+fn fill_empty(octant: &mut Octant, (r, g, b): Color) {
+    if octant.is_leaf() {
+        return;
+    }
+    if octant.child_mask() == 0 {
+        *octant = Octant::leaf(r,g,b);
+        return;
+    }
+    for i in 0..8 {
+        match octant.child_mut(i) {
+            Some(child) => fill_empty(child, (r, g, b)),
+            None => octant.set_child(i, Some(Octant::leaf(r,g,b))),
+        }
+    }
+    collapse(octant);
+}
+
+//Drops empty children and turns 8 leaves of the same colour back into a single leaf
+fn collapse(octant: &mut Octant) {
+    for i in 0..8 {
+        if matches!(octant.child(i), Some(child) if is_empty(child)) {
+            octant.set_child(i, None);
+        }
+    }
+    if octant.child_mask() != 0xFF {
+        return;
+    }
+    let color = octant.child(0).unwrap().color();
+    if octant.children().all(|(_, child)| child.is_leaf() && child.color() == color) {
+        let (r, g, b) = color;
+        *octant = Octant::leaf(r,g,b);
+    }
+}
+
+struct Combine<'a> {
+    other: &'a VoxelOctree,
+    op: Op,
+    priority: ColorPriority,
+    max_depth: u8,
+}
+
+impl<'a> Combine<'a> {
+    fn new(target: &VoxelOctree, other: &'a VoxelOctree, op: Op, priority: ColorPriority) -> Self {
+        //Deep enough that the target nodes are no bigger than the finest leaves of the other tree
+        let max_depth = match other.finest_grid() {
+            Some((_, unit)) => {
+                let ratio = (target.half_size * 2.0 / unit).max_element();
+                (ratio.log2().ceil().max(0.0) as u8).min(MAX_DEPTH)
+            },
+            None => 0,
+        };
+        Self {
+            other,
+            op,
+            priority,
+            max_depth,
+        }
+    }
+
+    //Moves the cursor down to `region` and looks at what the other tree has there
+    fn coverage(&self, cursor: Cursor<'a>, region: NodeBounds) -> (Coverage<'a>, Cursor<'a>) {
+        let other_bounds = self.other.bounds();
+        let (mut node, mut bounds) = match cursor {
+            Some(cursor) => cursor,
+            None if contains(other_bounds, region) => (&self.other.root, other_bounds),
+            None if is_empty(&self.other.root) || !overlaps(other_bounds, region) => return (Coverage::Empty, None),
+            None => return (Coverage::Mixed, None),
+        };
+
+        while !node.is_leaf() {
+            let i = bounds.child_index(region.center);
+            let child_bounds = bounds.child(i);
+            if !contains(child_bounds, region) {
+                break;
+            }
+            match node.child(i) {
+                Some(child) => {
+                    node = child;
+                    bounds = child_bounds;
+                },
+                None => return (Coverage::Empty, None),
+            }
+        }
+
+        let coverage = if node.is_leaf() {
+            Coverage::Full(node.color())
+        } else if node.child_mask() == 0 {
+            Coverage::Empty
+        } else if contains(region, bounds) {
+            Coverage::Same(node)
+        } else {
+            Coverage::Mixed
+        };
+        (coverage, Some((node, bounds)))
+    }
+
+    fn apply(&self, target: &mut Octant, region: NodeBounds, cursor: Cursor<'a>) {
+        let (coverage, cursor) = self.coverage(cursor, region);
+        match coverage {
+            Coverage::Empty => self.apply_empty(target),
+            Coverage::Full(color) => self.apply_full(target, color),
+            Coverage::Same(other) => self.apply_node(target, other),
+            Coverage::Mixed => self.apply_mixed(target, region, cursor),
+        }
+    }
+
+    fn apply_empty(&self, target: &mut Octant) {
+        if self.op == Op::Intersection {
+            *target = Octant::empty();
+        }
+    }
+
+    fn apply_full(&self, target: &mut Octant, (r, g, b): Color) {
+        match (self.op, self.priority) {
+            (Op::Union, ColorPriority::Other) => *target = Octant::leaf(r,g,b),
+            (Op::Union, ColorPriority::Target) => fill_empty(target, (r, g, b)),
+            (Op::Intersection, ColorPriority::Other) => recolor(target, (r, g, b)),
+            (Op::Intersection, ColorPriority::Target) => {},
+            (Op::Difference, _) => *target = Octant::empty(),
+        }
+    }
+
+    //Both nodes cover the same space, so their children line up as well
+    fn apply_node(&self, target: &mut Octant, other: &Octant) {
+        if other.is_leaf() {
+            return self.apply_full(target, other.color());
+        }
+        if other.child_mask() == 0 {
+            return self.apply_empty(target);
+        }
+
+        if is_empty(target) {
+            if self.op == Op::Union {
+                *target = other.clone();
+            }
+            return;
+        }
+        if target.is_leaf() {
+            let color = target.color();
+            match (self.op, self.priority) {
+                (Op::Union, ColorPriority::Target) => {},
+                (Op::Union, ColorPriority::Other) => {
+                    *target = other.clone();
+                    fill_empty(target, color);
+                },
+                (Op::Intersection, priority) => {
+                    *target = other.clone();
+                    if priority == ColorPriority::Target {
+                        recolor(target, color);
+                    }
+                },
+                (Op::Difference, _) => target.split(),
+            }
+            if target.is_leaf() || self.op != Op::Difference {
+                return;
+            }
+        }
+
+        for i in 0..8 {
+            match (target.child_mut(i), other.child(i)) {
+                (Some(target_child), Some(other_child)) => self.apply_node(target_child, other_child),
+                (Some(_), None) => {
+                    if self.op == Op::Intersection {
+                        target.set_child(i, None);
+                    }
+                },
+                (None, Some(other_child)) => {
+                    if self.op == Op::Union {
+                        target.set_child(i, Some(other_child.clone()));
+                    }
+                },
+                (None, None) => {},
+            }
+        }
+        collapse(target);
+    }
+
+    fn apply_mixed(&self, target: &mut Octant, region: NodeBounds, cursor: Cursor<'a>) {
+        if is_empty(target) && self.op != Op::Union {
+            return;
+        }
+        if region.depth >= self.max_depth {
+            //As fine as the other tree gets, so just sample it
+            return match self.other.get_voxel(region.center) {
+                Some(info) => self.apply_full(target, info.color),
+                None => self.apply_empty(target),
+            };
+        }
+
+        if target.is_leaf() {
+            target.split();
+        }
+        for i in 0..8 {
+            if !target.has_child(i) {
+                if self.op != Op::Union {
+                    continue;
+                }
+                target.set_child(i, Some(Octant::empty()));
+            }
+            self.apply(target.child_mut(i).unwrap(), region.child(i), cursor);
+        }
+        collapse(target);
+    }
+}
+
+impl VoxelOctree {
+    //Doubles the root towards `bounds` until they fit inside it, the old root becomes one of
+    //the children of the new one so all existing nodes keep their bounds
+    fn grow_to_fit(&mut self, bounds: NodeBounds) {
+        for _ in 0..MAX_DEPTH {
+            if contains(self.bounds(), bounds) {
+                return;
+            }
+            let mut sign = Vec3::ONE;
+            for i in 0..3 {
+                if bounds.center[i] < self.center[i] {
+                    sign[i] = -1.0;
+                }
+            }
+
+            let old_center = self.center;
+            self.center += self.half_size * sign;
+            self.half_size *= 2.0;
+            let old_root = std::mem::replace(&mut self.root, Octant::empty());
+            if !is_empty(&old_root) {
+                let i = self.bounds().child_index(old_center);
+                self.root.set_child(i, Some(old_root));
+            }
+        }
+    }
+
+    fn combine(&mut self, other: &VoxelOctree, op: Op, priority: ColorPriority) {
+        let combine = Combine::new(self, other, op, priority);
+        let bounds = self.bounds();
+        combine.apply(&mut self.root, bounds, None);
+        if self.root.child_mask() == 0 && !self.root.is_leaf() {
+            self.root = Octant::empty();
+        }
+        self.update_lod();
+    }
+
+    /// Adds everything that's solid in `other`, growing the root if `other` doesn't fit
+    /// inside it. `priority` picks the colour where both trees are solid.
+    pub fn union(&mut self, other: &VoxelOctree, priority: ColorPriority) {
+        if is_empty(&other.root) {
+            return;
+        }
+        self.grow_to_fit(other.bounds());
+        self.combine(other, Op::Union, priority);
+    }
+
+    /// Keeps only what's solid in both trees. `priority` picks the colour.
+    pub fn intersection(&mut self, other: &VoxelOctree, priority: ColorPriority) {
+        self.combine(other, Op::Intersection, priority);
+    }
+
+    /// Removes everything that's solid in `other`.
+    pub fn difference(&mut self, other: &VoxelOctree) {
+        self.combine(other, Op::Difference, ColorPriority::Target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::random_tree;
+
+    type Apply = fn(&mut VoxelOctree, &VoxelOctree);
+    type Expect = fn(Option<Color>, Option<Color>) -> Option<Color>;
+
+    //Every operation next to what it should do with a single point of both trees
+    const OPS: [(&str, Apply, Expect); 5] = [
+        ("union target", |a, b| a.union(b, ColorPriority::Target), |a, b| a.or(b)),
+        ("union other", |a, b| a.union(b, ColorPriority::Other), |a, b| b.or(a)),
+        ("intersection target", |a, b| a.intersection(b, ColorPriority::Target), |a, b| b.and(a)),
+        ("intersection other", |a, b| a.intersection(b, ColorPriority::Other), |a, b| a.and(b)),
+        ("difference", |a, b| a.difference(b), |a, b| if b.is_some() { None } else { a }),
+    ];
+
+    fn color(tree: &VoxelOctree, pos: Vec3) -> Option<Color> {
+        tree.get_voxel(pos).map(|info| info.color)
+    }
+
+    //Checks all operations between `a` and `b` at the centers of the cells of size `step`
+    //in the box from `min` to `max`
+    fn check_ops(a: &VoxelOctree, b: &VoxelOctree, min: Vec3, max: Vec3, step: f32) {
+        for &(name, apply, expect) in OPS.iter() {
+            let mut result = VoxelOctree::empty(a.center, a.half_size * 2.0);
+            result.root = a.root.clone();
+            apply(&mut result, b);
+            assert_pruned(&result.root, true);
+
+            let cells = ((max - min) / step).round();
+            for x in 0..cells.x as i32 {
+                for y in 0..cells.y as i32 {
+                    for z in 0..cells.z as i32 {
+                        let pos = min + (vec3(x as f32, y as f32, z as f32) + Vec3::splat(0.5)) * step;
+                        assert_eq!(color(&result, pos), expect(color(a, pos), color(b, pos)), "{} at {}", name, pos);
+                    }
+                }
+            }
+        }
+    }
+
+    //No empty interior nodes below the root
+    fn assert_pruned(octant: &Octant, root: bool) {
+        assert!(root || !is_empty(octant), "empty interior node");
+        for (_, child) in octant.children() {
+            assert_pruned(child, false);
+        }
+    }
+
+    #[test]
+    fn aligned_trees() {
+        for seed in 0..10 {
+            let (a, b) = (random_tree(seed * 2), random_tree(seed * 2 + 1));
+            check_ops(&a, &b, Vec3::splat(-8.0), Vec3::splat(8.0), 1.0);
+        }
+    }
+
+    #[test]
+    fn misaligned_trees() {
+        for seed in 0..5 {
+            let a = random_tree(seed * 2);
+            //Offset by whole voxels, but not by whole nodes of any other depth
+            let mut b = random_tree(seed * 2 + 1);
+            b.center = vec3(3.0, -5.0, 1.0);
+            check_ops(&a, &b, Vec3::splat(-8.0), Vec3::splat(8.0), 1.0);
+
+            //Half the size, so the voxels of `b` are smaller than the ones of `a`
+            let mut b = random_tree(seed * 2 + 1);
+            b.half_size = Vec3::splat(4.0);
+            b.center = vec3(2.0, 0.0, -4.0);
+            check_ops(&a, &b, Vec3::splat(-8.0), Vec3::splat(8.0), 0.5);
+        }
+    }
+
+    #[test]
+    fn union_grows_root() {
+        let a = random_tree(1);
+        let mut b = random_tree(2);
+        b.center = vec3(40.0, 0.0, -20.0);
+
+        let mut result = VoxelOctree::empty(a.center, a.half_size * 2.0);
+        result.root = a.root.clone();
+        result.union(&b, ColorPriority::Target);
+        //Doubled until it reaches from the old root out to `b`
+        assert!(contains(result.bounds(), b.bounds()) && contains(result.bounds(), a.bounds()));
+        assert_eq!(result.half_size, Vec3::splat(32.0));
+        assert_pruned(&result.root, true);
+        for &tree in [&a, &b].iter() {
+            for (octant, bounds) in tree.leaves() {
+                assert_eq!(color(&result, bounds.center), Some(octant.color()), "leaf at {}", bounds.center);
+            }
+        }
+        check_ops(&a, &b, vec3(-8.0, -8.0, -28.0), vec3(48.0, 8.0, 8.0), 1.0);
+    }
+
+    #[test]
+    fn results_collapse() {
+        //Two halves of the root that add up to all of it
+        let mut a = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        let mut b = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        for i in 0..8 {
+            let tree = if i & 4 == 0 { &mut a } else { &mut b };
+            tree.set_voxel(Octant::child_sign(i) * 4.0, 1, (5, 6, 7));
+        }
+        let mut union = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        union.root = a.root.clone();
+        union.union(&b, ColorPriority::Target);
+        assert!(union.root.is_leaf());
+
+        a.difference(&union);
+        assert!(is_empty(&a.root));
+    }
+
+    #[test]
+    fn grow_to_fit_keeps_nodes() {
+        let mut tree = random_tree(3);
+        let mut before: Vec<(Color, NodeBounds)> = tree.leaves().map(|(octant, bounds)| (octant.color(), bounds)).collect();
+        let target = NodeBounds { center: vec3(-30.0, 20.0, 5.0), half_size: Vec3::splat(2.0), depth: 0 };
+        tree.grow_to_fit(target);
+        assert!(contains(tree.bounds(), target));
+
+        //The old root is now a node a few levels down with the same bounds
+        let mut after: Vec<(Color, NodeBounds)> = tree.leaves().map(|(octant, bounds)| (octant.color(), bounds)).collect();
+        let min_depth = |leaves: &[(Color, NodeBounds)]| leaves.iter().map(|leaf| leaf.1.depth).min().unwrap();
+        let levels = min_depth(&after) - min_depth(&before);
+        assert_eq!(tree.half_size, Vec3::splat(8.0 * (1 << levels) as f32));
+        for leaf in after.iter_mut() {
+            leaf.1.depth -= levels;
+        }
+        let key = |leaf: &(Color, NodeBounds)| (leaf.1.depth, [leaf.1.center.x, leaf.1.center.y, leaf.1.center.z].map(f32::to_bits));
+        before.sort_by_key(key);
+        after.sort_by_key(key);
+        assert_eq!(before, after);
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use glam::*;
+
+use crate::octree::{raycast_tree, Color, NodeBounds, Octant, RayHit, RayNode, VoxelInfo, VoxelOctree};
+
+pub const NO_CHILD: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DagNode {
+    // Data layout:
+    // [  0-7] u8 child mask, a node without children is a leaf
+    // [ 8-15] u8 r
+    // [16-23] u8 g
+    // [24-31] u8 b
+    // Interior nodes hold the averaged colour of their children, like in the octree.
+    pub data: u32,
+    // Node indices of the children by octant index, `NO_CHILD` where there is none
+    pub children: [u32; 8],
+}
+
+impl DagNode {
+    pub fn child_mask(&self) -> u8 {
+        (self.data & 0xFF) as u8
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.child_mask() == 0
+    }
+
+    pub fn color(&self) -> Color {
+        ((self.data >> 8) as u8, (self.data >> 16) as u8, (self.data >> 24) as u8)
+    }
+
+    pub fn child(&self, i: usize) -> Option<usize> {
+        match self.children[i] {
+            NO_CHILD => None,
+            index => Some(index as usize),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DagStats {
+    pub octree_nodes: usize,
+    pub dag_nodes: usize,
+    /// Octree nodes per DAG node, higher is better.
+    pub compression_ratio: f32,
+}
+
+impl fmt::Display for DagStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} octree nodes -> {} DAG nodes ({:.2}x)", self.octree_nodes, self.dag_nodes, self.compression_ratio)
+    }
+}
+
+/// Sparse voxel DAG: an octree where identical subtrees are stored only once.
+pub struct SparseVoxelDag {
+    pub nodes: Vec<DagNode>,
+    pub root: Option<u32>,
+    pub center: Vec3,
+    pub half_size: Vec3,
+    octree_nodes: usize,
+}
+
+impl SparseVoxelDag {
+    pub fn from_octree(tree: &VoxelOctree) -> Self {
+        let mut dag = Self {
+            nodes: Vec::new(),
+            root: None,
+            center: tree.center,
+            half_size: tree.half_size,
+            octree_nodes: 0,
+        };
+        let mut lookup = HashMap::new();
+        dag.root = dag.add_octant(&tree.root, &mut lookup);
+        trace!("Built DAG: {}", dag.stats());
+        dag
+    }
+
+    //Children are added before their parent, so identical subtrees already share
+    //the same index by the time the parent gets hashed
+    fn add_octant(&mut self, octant: &Octant, lookup: &mut HashMap<DagNode, u32>) -> Option<u32> {
+        self.octree_nodes += 1;
+
+        let mut node = DagNode {
+            data: 0,
+            children: [NO_CHILD; 8],
+        };
+        if !octant.is_leaf() {
+            for (i, child) in octant.children() {
+                if let Some(index) = self.add_octant(child, lookup) {
+                    node.children[i] = index;
+                    node.data |= 1 << i;
+                }
+            }
+            if node.data == 0 {
+                //Subtree without any leaves
+                return None;
+            }
+        }
+        let (r, g, b) = octant.color();
+        node.data |= (r as u32) << 8 | (g as u32) << 16 | (b as u32) << 24;
+
+        let nodes = &mut self.nodes;
+        let index = *lookup.entry(node).or_insert_with(|| {
+            nodes.push(node);
+            (nodes.len() - 1) as u32
+        });
+        Some(index)
+    }
+
+    pub fn stats(&self) -> DagStats {
+        DagStats {
+            octree_nodes: self.octree_nodes,
+            dag_nodes: self.nodes.len(),
+            compression_ratio: self.octree_nodes as f32 / self.nodes.len().max(1) as f32,
+        }
+    }
+
+    pub fn bounds(&self) -> NodeBounds {
+        NodeBounds {
+            center: self.center,
+            half_size: self.half_size,
+            depth: 0,
+        }
+    }
+
+    /// Same as `VoxelOctree::get_voxel`, but walking the DAG.
+    pub fn get_voxel(&self, pos: Vec3) -> Option<VoxelInfo> {
+        let mut bounds = self.bounds();
+        if !bounds.contains(pos) {
+            return None;
+        }
+
+        let mut node = &self.nodes[self.root? as usize];
+        while !node.is_leaf() {
+            let i = bounds.child_index(pos);
+            node = &self.nodes[node.child(i)?];
+            bounds = bounds.child(i);
+        }
+
+        Some(VoxelInfo {
+            color: node.color(),
+            depth: bounds.depth,
+            center: bounds.center,
+            half_size: bounds.half_size,
+            is_leaf: true,
+            coverage: 1.0,
+        })
+    }
+
+    /// Same as `VoxelOctree::raycast`, but walking the DAG.
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<RayHit> {
+        let index = self.root? as usize;
+        raycast_tree(DagRef { dag: self, index }, self.bounds(), origin, dir, max_dist)
+    }
+}
+
+struct DagRef<'a> {
+    dag: &'a SparseVoxelDag,
+    index: usize,
+}
+
+impl<'a> RayNode for DagRef<'a> {
+    fn hit(&self, _bounds: NodeBounds, _distance: f32) -> Option<(Color, f32)> {
+        let node = &self.dag.nodes[self.index];
+        if node.is_leaf() {
+            Some((node.color(), 1.0))
+        } else {
+            None
+        }
+    }
+
+    fn child(&self, i: usize) -> Option<Self> {
+        let index = self.dag.nodes[self.index].child(i)?;
+        Some(DagRef { dag: self.dag, index })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::{check_rays, random_tree, Model};
+
+    #[test]
+    fn shares_identical_subtrees() {
+        let mut branch = Octant::empty();
+        branch.set_child(2, Some(Octant::leaf(1, 2, 3)));
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        tree.root.set_child(0, Some(branch.clone()));
+        tree.root.set_child(7, Some(branch));
+        //Ends without any leaves, so it's left out
+        tree.root.set_child(4, Some(Octant::empty()));
+        tree.update_lod();
+
+        let dag = SparseVoxelDag::from_octree(&tree);
+        assert_eq!((dag.stats().octree_nodes, dag.stats().dag_nodes), (6, 3));
+        let root = &dag.nodes[dag.root.unwrap() as usize];
+        assert_eq!(root.child_mask(), 1 << 0 | 1 << 7);
+        assert_eq!(root.child(0), root.child(7));
+        assert_eq!(root.child(4), None);
+
+        assert_eq!(SparseVoxelDag::from_octree(&VoxelOctree::empty(Vec3::ZERO, Vec3::ONE)).root, None);
+    }
+
+    #[test]
+    fn queries_match_octree() {
+        for seed in 0..10 {
+            let tree = random_tree(seed);
+            let dag = SparseVoxelDag::from_octree(&tree);
+            for i in 0..16 * 16 * 16 {
+                let pos = Model::center(i);
+                assert_eq!(dag.get_voxel(pos), tree.get_voxel(pos), "voxel at {}", pos);
+            }
+            let leaves: Vec<(Color, NodeBounds)> = tree.leaves().map(|(octant, bounds)| (octant.color(), bounds)).collect();
+            check_rays(seed, &leaves, |origin, dir, max_dist| {
+                let hit = dag.raycast(origin, dir, max_dist);
+                assert_eq!(hit, tree.raycast(origin, dir, max_dist));
+                hit
+            });
+        }
+    }
+}
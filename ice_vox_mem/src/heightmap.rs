@@ -0,0 +1,485 @@
+// Terrain from heightmap images.
+//
+// Every pixel of the heightmap is a column of voxels standing on y = 0, with pixel (0, 0)
+// at the origin and rows going towards +z. An optional colour map of the same size gives
+// every column its colour.
+//
+// On load the heights are summarized in a min/max pyramid, where every level halves the
+// resolution of the one below. An octant only has to look at a few cells of the level that
+// matches its size to know the lowest and highest column under it, so everything above the
+// highest column or below the lowest one is decided without subdividing.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+
+use glam::*;
+
+use crate::octree::{is_leaf_octant, Color, OctantFillState, VoxelOctree, DEFAULT_COLOR, MAX_GENERATE_DEPTH};
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    Io(io::Error),
+    Png(png::DecodingError),
+    /// The colour map doesn't have the same size as the heightmap.
+    SizeMismatch,
+    /// The `HeightmapParams` can't be used, with a description of what was wrong.
+    InvalidParams(&'static str),
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeightmapError::Io(e) => write!(f, "io error: {}", e),
+            HeightmapError::Png(e) => write!(f, "png error: {}", e),
+            HeightmapError::SizeMismatch => write!(f, "colour map size doesn't match the heightmap"),
+            HeightmapError::InvalidParams(reason) => write!(f, "invalid heightmap parameters: {}", reason),
+        }
+    }
+}
+
+impl Error for HeightmapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HeightmapError::Io(e) => Some(e),
+            HeightmapError::Png(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HeightmapError {
+    fn from(e: io::Error) -> Self {
+        HeightmapError::Io(e)
+    }
+}
+
+impl From<png::DecodingError> for HeightmapError {
+    fn from(e: png::DecodingError) -> Self {
+        HeightmapError::Png(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeightmapParams {
+    /// Size of a voxel, and of a pixel of the heightmap, in world units.
+    pub voxel_size: f32,
+    /// Height of a white pixel in world units, black is 0.
+    pub vertical_scale: f32,
+    /// Colour of the terrain when there is no colour map.
+    pub color: Color,
+}
+
+impl Default for HeightmapParams {
+    fn default() -> Self {
+        Self {
+            voxel_size: 1.0,
+            vertical_scale: 64.0,
+            color: DEFAULT_COLOR,
+        }
+    }
+}
+
+//Decodes a png into rgb values between 0 and 1, greyscale images have the same value in all
+//three channels and alpha is ignored
+fn read_png<R: Read>(reader: R) -> Result<(u32, u32, Vec<Vec3>), HeightmapError> {
+    let mut decoder = png::Decoder::new(reader);
+    //Palettes and bit depths below 8 get expanded to plain 8 bit channels
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer)?;
+
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::RGB | png::ColorType::Indexed => 3,
+        png::ColorType::RGBA => 4,
+    };
+    let bytes = if info.bit_depth == png::BitDepth::Sixteen { 2 } else { 1 };
+    let sample = |row: &[u8], i: usize| -> f32 {
+        if bytes == 2 {
+            u16::from_be_bytes([row[i * 2], row[i * 2 + 1]]) as f32 / 65535.0
+        } else {
+            row[i] as f32 / 255.0
+        }
+    };
+
+    let mut pixels = Vec::with_capacity(info.width as usize * info.height as usize);
+    for row in buffer.chunks(info.line_size).take(info.height as usize) {
+        for x in 0..info.width as usize {
+            let i = x * channels;
+            pixels.push(if channels < 3 {
+                Vec3::splat(sample(row, i))
+            } else {
+                vec3(sample(row, i), sample(row, i + 1), sample(row, i + 2))
+            });
+        }
+    }
+    Ok((info.width, info.height, pixels))
+}
+
+//Lowest and highest column in a cell of the pyramid, and its colour if all columns share it
+#[derive(Clone, Copy, PartialEq)]
+struct Cell {
+    min: f32,
+    max: f32,
+    color: Option<Color>,
+}
+
+impl Cell {
+    fn merge(self, other: Cell) -> Cell {
+        Cell {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            color: if self.color == other.color { self.color } else { None },
+        }
+    }
+}
+
+pub struct Heightmap {
+    width: u32,
+    height: u32,
+    //Height of every pixel between 0 and 1, row by row
+    heights: Vec<f32>,
+    colors: Option<Vec<Color>>,
+    //Level 0 has a cell per pixel, padded to a power of two along both axes with empty columns
+    pyramid: Vec<Vec<Cell>>,
+}
+
+impl Heightmap {
+    /// Builds a heightmap from heights between 0 and 1, row by row. `colors` optionally gives
+    /// every pixel its colour.
+    pub fn new(width: u32, height: u32, heights: Vec<f32>, colors: Option<Vec<Color>>) -> Result<Self, HeightmapError> {
+        let pixels = width as usize * height as usize;
+        if heights.len() != pixels || colors.as_ref().map_or(pixels, |colors| colors.len()) != pixels {
+            return Err(HeightmapError::SizeMismatch);
+        }
+        let mut heightmap = Self {
+            width,
+            height,
+            heights,
+            colors,
+            pyramid: Vec::new(),
+        };
+        heightmap.build_pyramid();
+        Ok(heightmap)
+    }
+
+    /// Reads a greyscale png heightmap, and optionally a png colour map of the same size.
+    /// Colour images used as heightmap are converted to greyscale.
+    pub fn read<R: Read, C: Read>(reader: R, color_map: Option<C>) -> Result<Self, HeightmapError> {
+        let (width, height, pixels) = read_png(reader)?;
+        let heights = pixels.iter().map(|p| p.dot(vec3(0.2126, 0.7152, 0.0722)).min(1.0)).collect();
+
+        let colors = match color_map {
+            Some(color_map) => {
+                let (color_width, color_height, pixels) = read_png(color_map)?;
+                if (color_width, color_height) != (width, height) {
+                    return Err(HeightmapError::SizeMismatch);
+                }
+                let to_u8 = |v: f32| (v * 255.0).round() as u8;
+                Some(pixels.iter().map(|p| (to_u8(p.x), to_u8(p.y), to_u8(p.z))).collect())
+            },
+            None => None,
+        };
+        Self::new(width, height, heights, colors)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Height of the pixel at `(x, y)` between 0 and 1, 0 outside the image.
+    pub fn value(&self, x: i64, y: i64) -> f32 {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return 0.0;
+        }
+        self.heights[y as usize * self.width as usize + x as usize]
+    }
+
+    /// Colour of the pixel at `(x, y)` in the colour map, or `default` without one.
+    pub fn color(&self, x: i64, y: i64, default: Color) -> Color {
+        match &self.colors {
+            Some(colors) if x >= 0 && y >= 0 && x < self.width as i64 && y < self.height as i64 => {
+                colors[y as usize * self.width as usize + x as usize]
+            },
+            _ => default,
+        }
+    }
+
+    //Number of cells along x and y in a level of the pyramid. Both axes are halved on every
+    //level until they're down to a single cell, so long strips stay small.
+    fn level_size(&self, level: usize) -> (usize, usize) {
+        let width = self.width.max(1).next_power_of_two() as usize;
+        let height = self.height.max(1).next_power_of_two() as usize;
+        ((width >> level).max(1), (height >> level).max(1))
+    }
+
+    fn build_pyramid(&mut self) {
+        let (width, height) = self.level_size(0);
+        let mut level = Vec::with_capacity(width * height);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let value = self.value(x, y);
+                let inside = x < self.width as i64 && y < self.height as i64;
+                level.push(Cell {
+                    min: value,
+                    max: value,
+                    color: if inside { Some(self.color(x, y, DEFAULT_COLOR)) } else { None },
+                });
+            }
+        }
+
+        self.pyramid = vec![level];
+        while self.level_size(self.pyramid.len() - 1) != (1, 1) {
+            let (below_width, below_height) = self.level_size(self.pyramid.len() - 1);
+            let (width, height) = self.level_size(self.pyramid.len());
+            let below = self.pyramid.last().unwrap();
+            let mut level = Vec::with_capacity(width * height);
+            for y in 0..height {
+                for x in 0..width {
+                    //An axis that's already a single cell wide merges that cell with itself
+                    let cell = |dx: usize, dy: usize| below[(y * 2 + dy).min(below_height - 1) * below_width + (x * 2 + dx).min(below_width - 1)];
+                    level.push(cell(0, 0).merge(cell(1, 0)).merge(cell(0, 1)).merge(cell(1, 1)));
+                }
+            }
+            self.pyramid.push(level);
+        }
+    }
+
+    //Summary of the pixels from `min` to `max`, inclusive. Picks the finest level where
+    //the range spans at most 2x2 cells, which cover the range and a bit more
+    fn cell_range(&self, min: (i64, i64), max: (i64, i64)) -> Cell {
+        let (width, height) = self.level_size(0);
+        let (width, height) = (width as i64, height as i64);
+        if max.0 < 0 || max.1 < 0 || min.0 >= width || min.1 >= height {
+            return Cell { min: 0.0, max: 0.0, color: None };
+        }
+        let (x0, y0) = (min.0.max(0), min.1.max(0));
+        let (x1, y1) = (max.0.min(width - 1), max.1.min(height - 1));
+
+        let mut level = 0;
+        while (x1 >> level) - (x0 >> level) > 1 || (y1 >> level) - (y0 >> level) > 1 {
+            level += 1;
+        }
+        let cells = &self.pyramid[level];
+        let level_width = self.level_size(level).0;
+        let mut cell: Option<Cell> = None;
+        for y in (y0 >> level)..=(y1 >> level) {
+            for x in (x0 >> level)..=(x1 >> level) {
+                let next = cells[y as usize * level_width + x as usize];
+                cell = Some(cell.map_or(next, |cell| cell.merge(next)));
+            }
+        }
+        let mut cell = cell.unwrap();
+        //Part of the range lies outside the pyramid, where there's nothing
+        if (x0, y0) != min || (x1, y1) != max {
+            cell = cell.merge(Cell { min: 0.0, max: 0.0, color: None });
+        }
+        cell
+    }
+
+    /// Classifier for `VoxelOctree::generate`. `leaf_size` is the voxel size at max depth,
+    /// which should match `params.voxel_size` for the columns to line up with the voxels.
+    pub fn classifier(&self, params: HeightmapParams, leaf_size: Vec3) -> impl Fn(Vec3, Vec3, Vec3) -> OctantFillState + Copy + '_ {
+        move |center, inner, outer| {
+            let pixel = |v: f32| (v / params.voxel_size).floor() as i64;
+            if is_leaf_octant(inner, outer, leaf_size) {
+                let (x, y) = (pixel(center.x), pixel(center.z));
+                let surface = self.value(x, y) * params.vertical_scale;
+                return if center.y >= 0.0 && center.y < surface {
+                    OctantFillState::Full(self.color(x, y, params.color))
+                } else {
+                    OctantFillState::Empty
+                };
+            }
+
+            let half_size = (outer - inner).abs() * 0.5;
+            let (min, max) = (center - half_size, center + half_size);
+            //Every pixel that overlaps the octant
+            let last = |v: f32| (v / params.voxel_size).ceil() as i64 - 1;
+            let cell = self.cell_range((pixel(min.x), pixel(min.z)), (last(max.x), last(max.z)));
+            let (lowest, highest) = (cell.min * params.vertical_scale, cell.max * params.vertical_scale);
+
+            if min.y >= highest || max.y <= 0.0 {
+                OctantFillState::Empty
+            } else if min.y >= 0.0 && max.y <= lowest && cell.color.is_some() {
+                //Without a colour map every cell has the same placeholder colour
+                let color = if self.colors.is_some() { cell.color.unwrap() } else { params.color };
+                OctantFillState::Full(color)
+            } else {
+                OctantFillState::ContainsVoxel(params.color)
+            }
+        }
+    }
+
+    /// Builds an octree just big enough for the heightmap, with every pixel a column of
+    /// voxels at max depth. The tree is at most 2^24 voxels wide, anything beyond that is
+    /// cut off.
+    pub fn to_octree(&self, params: HeightmapParams) -> Result<VoxelOctree, HeightmapError> {
+        if !(params.voxel_size > 0.0 && params.voxel_size.is_finite()) {
+            return Err(HeightmapError::InvalidParams("voxel size has to be positive and finite"));
+        }
+        if !(params.vertical_scale >= 0.0 && params.vertical_scale.is_finite()) {
+            return Err(HeightmapError::InvalidParams("vertical scale has to be positive and finite"));
+        }
+
+        let max_extent = 1u64 << MAX_GENERATE_DEPTH;
+        let top = self.heights.iter().cloned().fold(0.0, f32::max) * params.vertical_scale / params.voxel_size;
+        //Also catches a height so large it turned into infinity
+        let top = if top < max_extent as f32 { top.ceil() as u64 } else { max_extent };
+        let extent = (self.width.max(self.height) as u64).max(top).clamp(1, max_extent);
+        let depth = 64 - (extent - 1).leading_zeros();
+        let size = (1u64 << depth) as f32 * params.voxel_size;
+        if !size.is_finite() {
+            return Err(HeightmapError::InvalidParams("voxel size too large for the heightmap"));
+        }
+
+        let mut tree = VoxelOctree::empty(Vec3::splat(size / 2.0), Vec3::splat(size));
+        tree.generate_heightmap(self, params, depth as u8);
+        Ok(tree)
+    }
+}
+
+impl VoxelOctree {
+    /// Fills the tree with the columns of `heightmap` down to voxels of `max_depth`. For the
+    /// columns to line up with the pixels, the voxels should be `params.voxel_size` big.
+    pub fn generate_heightmap(&mut self, heightmap: &Heightmap, params: HeightmapParams, max_depth: u8) -> usize {
+        let leaf_size = self.leaf_size(max_depth);
+        let nodes_generated = self.generate(max_depth, heightmap.classifier(params, leaf_size));
+        trace!("Heightmap nodes generated: {}", nodes_generated);
+        nodes_generated
+    }
+
+    /// Reads a png heightmap, and optionally a png colour map, and builds an octree from it.
+    pub fn import_heightmap<R: Read, C: Read>(reader: R, color_map: Option<C>, params: HeightmapParams) -> Result<Self, HeightmapError> {
+        Heightmap::read(reader, color_map)?.to_octree(params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(width: u32, height: u32, color: png::ColorType, depth: png::BitDepth, palette: Option<Vec<u8>>, data: &[u8]) -> Vec<u8> {
+        let mut png = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png, width, height);
+            encoder.set_color(color);
+            encoder.set_depth(depth);
+            if let Some(palette) = palette {
+                encoder.set_palette(palette);
+            }
+            encoder.write_header().unwrap().write_image_data(data).unwrap();
+        }
+        png
+    }
+
+    fn heightmap() -> Heightmap {
+        let heights = vec![0.0, 0.25, 0.5, 1.0, 0.75, 0.0];
+        let colors = (0..6).map(|i| (i * 40, 0, 0)).collect();
+        Heightmap::new(3, 2, heights, Some(colors)).unwrap()
+    }
+
+    //Every voxel of the tree has to be solid exactly where it's below the surface of its column
+    fn assert_columns(tree: &VoxelOctree, map: &Heightmap, params: HeightmapParams) {
+        let top = (params.vertical_scale / params.voxel_size).ceil() as i64 + 1;
+        for x in 0..map.width() as i64 + 1 {
+            for z in 0..map.height() as i64 + 1 {
+                for y in 0..top {
+                    let pos = (vec3(x as f32, y as f32, z as f32) + Vec3::splat(0.5)) * params.voxel_size;
+                    let surface = map.value(x, z) * params.vertical_scale;
+                    let expected = if pos.y < surface { Some(map.color(x, z, params.color)) } else { None };
+                    assert_eq!(tree.get_voxel(pos).map(|v| v.color), expected, "voxel at {}", pos);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn columns_match_heights() {
+        let params = HeightmapParams { voxel_size: 0.5, vertical_scale: 2.0, ..HeightmapParams::default() };
+        let map = heightmap();
+        assert_columns(&map.to_octree(params).unwrap(), &map, params);
+    }
+
+    #[test]
+    fn non_square_maps() {
+        let params = HeightmapParams { voxel_size: 1.0, vertical_scale: 6.0, ..HeightmapParams::default() };
+        for &(width, height) in [(37, 5), (1, 19), (16, 3)].iter() {
+            let heights = (0..width * height).map(|i| (i * 7919 % 23) as f32 / 22.0).collect();
+            let map = Heightmap::new(width, height, heights, None).unwrap();
+            assert_columns(&map.to_octree(params).unwrap(), &map, params);
+        }
+
+        //A long strip only needs a pyramid as big as the strip itself
+        let map = Heightmap::new(16384, 1, vec![0.5; 16384], None).unwrap();
+        assert_eq!(map.pyramid[0].len(), 16384);
+        assert_eq!(map.pyramid.iter().map(|level| level.len()).sum::<usize>(), 2 * 16384 - 1);
+    }
+
+    #[test]
+    fn invalid_params() {
+        let map = heightmap();
+        for &(voxel_size, vertical_scale) in &[(0.0, 1.0), (-1.0, 1.0), (f32::NAN, 1.0), (f32::INFINITY, 1.0), (1.0, f32::INFINITY), (1.0, -1.0)] {
+            let params = HeightmapParams { voxel_size, vertical_scale, ..HeightmapParams::default() };
+            assert!(matches!(map.to_octree(params), Err(HeightmapError::InvalidParams(_))), "{:?}", params);
+        }
+        let params = HeightmapParams { voxel_size: 1e38, ..HeightmapParams::default() };
+        assert!(matches!(map.to_octree(params), Err(HeightmapError::InvalidParams(_))));
+    }
+
+    #[test]
+    fn size_mismatch() {
+        assert!(matches!(Heightmap::new(2, 2, vec![0.0; 3], None), Err(HeightmapError::SizeMismatch)));
+        assert!(matches!(Heightmap::new(2, 2, vec![0.0; 4], Some(vec![(0, 0, 0); 5])), Err(HeightmapError::SizeMismatch)));
+    }
+
+    #[test]
+    fn import_png() {
+        let params = HeightmapParams { voxel_size: 1.0, vertical_scale: 8.0, ..HeightmapParams::default() };
+        let expected = [0.0, 0.25, 0.5, 1.0, 0.75, 0.125];
+        let grey8: Vec<u8> = expected.iter().map(|h| (h * 255.0f32).round() as u8).collect();
+        let grey16: Vec<u8> = expected.iter().flat_map(|h| ((h * 65535.0f32).round() as u16).to_be_bytes()).collect();
+        let colors: Vec<Color> = (0..6).map(|i| (i * 40, 255 - i * 40, 7)).collect();
+        let rgb: Vec<u8> = colors.iter().flat_map(|&(r, g, b)| [r, g, b]).collect();
+        let color_map = encode(3, 2, png::ColorType::RGB, png::BitDepth::Eight, None, &rgb);
+        let indexed_map = encode(3, 2, png::ColorType::Indexed, png::BitDepth::Eight, Some(rgb.clone()), &[0, 1, 2, 3, 4, 5]);
+
+        let heightmaps = [
+            (encode(3, 2, png::ColorType::Grayscale, png::BitDepth::Eight, None, &grey8), 1.0 / 255.0),
+            (encode(3, 2, png::ColorType::Grayscale, png::BitDepth::Sixteen, None, &grey16), 1.0 / 65535.0),
+        ];
+        for (heightmap, precision) in heightmaps.iter() {
+            for color_map in [None, Some(&color_map), Some(&indexed_map)].iter() {
+                let map = Heightmap::read(&heightmap[..], color_map.map(|png| &png[..])).unwrap();
+                assert_eq!((map.width(), map.height()), (3, 2));
+                for (i, h) in expected.iter().enumerate() {
+                    let (x, y) = (i as i64 % 3, i as i64 / 3);
+                    assert!((map.value(x, y) - h).abs() <= *precision, "height at {} {}", x, y);
+                    let color = if color_map.is_some() { colors[i] } else { params.color };
+                    assert_eq!(map.color(x, y, params.color), color, "colour at {} {}", x, y);
+                }
+
+                let tree = VoxelOctree::import_heightmap(&heightmap[..], color_map.map(|png| &png[..]), params).unwrap();
+                assert_columns(&tree, &map, params);
+            }
+        }
+    }
+
+    #[test]
+    fn import_png_errors() {
+        let heightmap = encode(3, 2, png::ColorType::Grayscale, png::BitDepth::Eight, None, &[0; 6]);
+        let color_map = encode(2, 3, png::ColorType::RGB, png::BitDepth::Eight, None, &[0; 18]);
+        let params = HeightmapParams::default();
+        let result = VoxelOctree::import_heightmap(&heightmap[..], Some(&color_map[..]), params);
+        assert!(matches!(result, Err(HeightmapError::SizeMismatch)));
+        let result = VoxelOctree::import_heightmap(&heightmap[..10], None::<&[u8]>, params);
+        assert!(matches!(result, Err(HeightmapError::Png(_))));
+    }
+}
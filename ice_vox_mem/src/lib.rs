@@ -1,3 +1,15 @@
 #[macro_use] extern crate log;
 
 pub mod octree;
+pub mod packed;
+pub mod dag;
+pub mod serialize;
+pub mod vox;
+pub mod mesh;
+pub mod sdf;
+pub mod csg;
+pub mod noise;
+pub mod terrain;
+pub mod caves;
+pub mod heightmap;
+mod marching_cubes;
@@ -0,0 +1,321 @@
+// Marching cubes over the cells of the finest leaf depth. Every cell center is a sample and
+// the cubes run between the centers of 2x2x2 neighbouring cells.
+//
+// The density of a cell is half its own occupancy and half a [1 2 1] blur of the occupancy
+// around it. Solid cells always end up above the iso level and empty ones below it, so the
+// surface keeps the topology of the voxels while the blur smooths out where it crosses the
+// edges between them.
+//
+// Instead of the usual 256 case lookup table, every cube is triangulated by connecting the
+// crossings on each of its faces into segments and chaining those into loops. A face with
+// two diagonal solid corners is resolved with the asymptotic decider, which only looks at
+// the four corners of that face, so both cubes sharing it always agree and the mesh stays
+// watertight.
+
+use std::collections::{HashMap, HashSet};
+
+use glam::*;
+
+use crate::mesh::Mesh;
+use crate::octree::{Color, VoxelOctree};
+
+const ISO_LEVEL: f32 = 0.5;
+
+//An edge of the sample grid, as the cell it starts at and the axis it runs along
+type Edge = ([i64; 3], usize);
+
+fn offset(p: [i64; 3], d: [i64; 3]) -> [i64; 3] {
+    [p[0] + d[0], p[1] + d[1], p[2] + d[2]]
+}
+
+fn unit_offset(axis: usize, sign: i64) -> [i64; 3] {
+    let mut d = [0; 3];
+    d[axis] = sign;
+    d
+}
+
+//Offsets and weights of the [1 2 1] kernel in 3D, the weights add up to 64
+fn kernel() -> impl Iterator<Item = ([i64; 3], f32)> {
+    (0..27).map(|i| {
+        let d = [i / 9 - 1, i / 3 % 3 - 1, i % 3 - 1];
+        let weight = d.iter().map(|&x| if x == 0 { 2.0 } else { 1.0 }).product();
+        (d, weight)
+    })
+}
+
+//Cube corners use the same bit layout as octant indices: bit 2 = +x, bit 1 = +y, bit 0 = +z
+fn corner_offset(corner: usize) -> [i64; 3] {
+    [(corner >> 2 & 1) as i64, (corner >> 1 & 1) as i64, (corner & 1) as i64]
+}
+
+fn corner_index(p: [i64; 3]) -> usize {
+    (p[0] << 2 | p[1] << 1 | p[2]) as usize
+}
+
+struct MarchingCubes<'a> {
+    tree: &'a VoxelOctree,
+    root_min: Vec3,
+    unit: Vec3,
+    cells: HashMap<[i64; 3], Option<Color>>,
+    densities: HashMap<[i64; 3], f32>,
+    vertices: HashMap<Edge, u32>,
+}
+
+impl<'a> MarchingCubes<'a> {
+    fn cell(&mut self, p: [i64; 3]) -> Option<Color> {
+        let (tree, root_min, unit) = (self.tree, self.root_min, self.unit);
+        *self.cells.entry(p).or_insert_with(|| {
+            let center = root_min + (vec3(p[0] as f32, p[1] as f32, p[2] as f32) + Vec3::splat(0.5)) * unit;
+            tree.get_voxel(center).map(|info| info.color)
+        })
+    }
+
+    fn occupancy(&mut self, p: [i64; 3]) -> f32 {
+        if self.cell(p).is_some() { 1.0 } else { 0.0 }
+    }
+
+    fn density(&mut self, p: [i64; 3]) -> f32 {
+        if let Some(&density) = self.densities.get(&p) {
+            return density;
+        }
+        let blur = kernel().map(|(d, weight)| weight * self.occupancy(offset(p, d))).sum::<f32>() / 64.0;
+        let density = 0.5 * self.occupancy(p) + 0.5 * blur;
+        self.densities.insert(p, density);
+        density
+    }
+
+    fn gradient(&mut self, p: [i64; 3]) -> Vec3 {
+        let mut gradient = Vec3::ZERO;
+        for axis in 0..3 {
+            let forward = self.density(offset(p, unit_offset(axis, 1)));
+            let backward = self.density(offset(p, unit_offset(axis, -1)));
+            gradient[axis] = (forward - backward) / (2.0 * self.unit[axis]);
+        }
+        gradient
+    }
+
+    //Kernel weighted average colour of the solid cells around `p`
+    fn color(&mut self, p: [i64; 3]) -> Vec3 {
+        let mut sum = Vec3::ZERO;
+        let mut total = 0.0;
+        for (d, weight) in kernel() {
+            if let Some((r, g, b)) = self.cell(offset(p, d)) {
+                sum += vec3(r as f32, g as f32, b as f32) * weight;
+                total += weight;
+            }
+        }
+        if total > 0.0 { sum / total } else { Vec3::ZERO }
+    }
+
+    //Vertex where the surface crosses `edge`, shared by every cube around that edge
+    fn vertex(&mut self, mesh: &mut Mesh, edge: Edge) -> u32 {
+        if let Some(&index) = self.vertices.get(&edge) {
+            return index;
+        }
+
+        let (from, axis) = edge;
+        let to = offset(from, unit_offset(axis, 1));
+        let (d0, d1) = (self.density(from), self.density(to));
+        let t = (ISO_LEVEL - d0) / (d1 - d0);
+
+        let mut cell = vec3(from[0] as f32, from[1] as f32, from[2] as f32) + Vec3::splat(0.5);
+        cell[axis] += t;
+        let position = self.root_min + cell * self.unit;
+
+        //Density goes up towards the solid side, so the normal points down the gradient
+        let gradient = self.gradient(from).lerp(self.gradient(to), t);
+        let normal = match (-gradient).try_normalize() {
+            Some(normal) => normal,
+            None => {
+                let mut normal = Vec3::ZERO;
+                normal[axis] = if d0 > d1 { 1.0 } else { -1.0 };
+                normal
+            },
+        };
+
+        let color = self.color(from).lerp(self.color(to), t).round();
+        let index = mesh.push_vertex(position, normal, (color.x as u8, color.y as u8, color.z as u8));
+        self.vertices.insert(edge, index);
+        index
+    }
+
+    fn march(&mut self, mesh: &mut Mesh, cube: [i64; 3]) {
+        let mut density = [0.0; 8];
+        for (corner, d) in density.iter_mut().enumerate() {
+            *d = self.density(offset(cube, corner_offset(corner)));
+        }
+        let solid = density.map(|d| d > ISO_LEVEL);
+
+        //Every crossing is left through exactly one face of the cube, so this maps each one
+        //to the next crossing along the loop it's part of
+        let mut next: HashMap<Edge, Edge> = HashMap::new();
+        for axis in 0..3 {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            for &side in [0, 1].iter() {
+                //Face corners counter clockwise seen from outside the cube
+                let mut corners = [[0; 3]; 4];
+                for (c, &(cu, cv)) in [(0, 0), (1, 0), (1, 1), (0, 1)].iter().enumerate() {
+                    corners[c][axis] = side;
+                    corners[c][u] = cu;
+                    corners[c][v] = cv;
+                }
+                if side == 0 {
+                    corners.reverse();
+                }
+                let corners = corners.map(corner_index);
+
+                //Crossings in the same order, flagged when they go from empty into solid
+                let mut crossings: Vec<(Edge, bool)> = Vec::with_capacity(4);
+                for c in 0..4 {
+                    let (a, b) = (corners[c], corners[(c + 1) % 4]);
+                    if solid[a] != solid[b] {
+                        let (lo, hi) = (corner_offset(a.min(b)), corner_offset(a.max(b)));
+                        let edge_axis = (0..3).find(|&i| lo[i] != hi[i]).unwrap();
+                        crossings.push(((offset(cube, lo), edge_axis), solid[b]));
+                    }
+                }
+
+                //With two diagonal solid corners, the saddle point of the bilinear
+                //interpolation decides whether they are connected across the face
+                let connected = crossings.len() == 4 && {
+                    let [d0, d1, d2, d3] = corners.map(|c| density[c]);
+                    (d0 * d2 - d1 * d3) / (d0 + d2 - d1 - d3) >= ISO_LEVEL
+                };
+
+                //A segment runs from where the outline enters the solid to where it leaves
+                //it, with the solid on its right seen from outside the cube
+                let n = crossings.len();
+                for i in 0..n {
+                    let (edge, entering) = crossings[i];
+                    if entering {
+                        let exit = if connected { crossings[(i + n - 1) % n] } else { crossings[(i + 1) % n] };
+                        next.insert(edge, exit.0);
+                    }
+                }
+            }
+        }
+
+        //Chain the segments into loops and fan each one out into triangles
+        let mut edges: Vec<Edge> = next.keys().cloned().collect();
+        edges.sort_unstable();
+        let mut visited: HashSet<Edge> = HashSet::new();
+        for start in edges {
+            if visited.contains(&start) {
+                continue;
+            }
+            let mut outline = Vec::new();
+            let mut edge = start;
+            while visited.insert(edge) {
+                outline.push(self.vertex(mesh, edge));
+                edge = next[&edge];
+            }
+            for i in 1..outline.len().saturating_sub(1) {
+                mesh.indices.extend_from_slice(&[outline[0], outline[i], outline[i + 1]]);
+            }
+        }
+    }
+}
+
+impl VoxelOctree {
+    pub(crate) fn marching_cubes(&self, mesh: &mut Mesh) {
+        let unit = match self.finest_grid() {
+            Some((_, unit)) => unit,
+            None => return,
+        };
+
+        //Any cube the surface passes through has an edge between a solid and an empty cell,
+        //which is a visible face of the blocky mesh, so only the cubes around those are needed
+        let mut cubes: HashSet<[i64; 3]> = HashSet::new();
+        for ((axis, _, plane), faces) in self.visible_faces(unit) {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            for face in faces {
+                for cu in face.u - 1..face.u + face.size {
+                    for cv in face.v - 1..face.v + face.size {
+                        let mut cube = [0; 3];
+                        cube[axis] = plane - 1;
+                        cube[u] = cu;
+                        cube[v] = cv;
+                        cubes.insert(cube);
+                    }
+                }
+            }
+        }
+        //Sorted so the same tree always produces the same mesh
+        let mut cubes: Vec<[i64; 3]> = cubes.into_iter().collect();
+        cubes.sort_unstable();
+
+        let mut marcher = MarchingCubes {
+            tree: self,
+            root_min: self.bounds().min(),
+            unit,
+            cells: HashMap::new(),
+            densities: HashMap::new(),
+            vertices: HashMap::new(),
+        };
+        for cube in cubes {
+            marcher.march(mesh, cube);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::random_tree;
+
+    //Every edge of the mesh has to be used once in each direction, by two triangles facing
+    //the same way
+    fn assert_watertight(tree: &VoxelOctree) -> Mesh {
+        let mut mesh = Mesh::new();
+        tree.marching_cubes(&mut mesh);
+        let mut edges: HashMap<(u32, u32), u32> = HashMap::new();
+        for tri in mesh.indices.chunks_exact(3) {
+            for i in 0..3 {
+                let (a, b) = (tri[i], tri[(i + 1) % 3]);
+                assert_ne!(a, b, "degenerate triangle");
+                *edges.entry((a, b)).or_default() += 1;
+            }
+        }
+        for (&(a, b), &count) in edges.iter() {
+            assert_eq!((count, edges.get(&(b, a))), (1, Some(&1)), "edge {} {}", a, b);
+        }
+        mesh
+    }
+
+    #[test]
+    fn random_trees_are_watertight() {
+        for seed in 0..20 {
+            assert_watertight(&random_tree(seed));
+        }
+    }
+
+    #[test]
+    fn saddles_are_watertight() {
+        //Voxels that only share an edge or a corner put two diagonal solid corners on faces
+        //and into cubes, which is where the asymptotic decider comes in
+        let voxels: [&[[f32; 3]]; 4] = [
+            &[[0.5, 0.5, 0.5], [1.5, 1.5, 0.5]],
+            &[[0.5, 0.5, 0.5], [1.5, 1.5, 1.5]],
+            &[[0.5, 0.5, 0.5], [1.5, 1.5, 0.5], [1.5, 0.5, 1.5], [0.5, 1.5, 1.5]],
+            &[[1.5, 0.5, 0.5], [0.5, 1.5, 0.5], [0.5, 0.5, 1.5], [1.5, 1.5, 1.5]],
+        ];
+        for voxels in voxels.iter() {
+            let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+            for &p in voxels.iter() {
+                tree.set_voxel(Vec3::from(p), 4, (10, 20, 30));
+            }
+            assert!(!assert_watertight(&tree).is_empty());
+        }
+    }
+
+    #[test]
+    fn empty_and_full_trees() {
+        let mut mesh = Mesh::new();
+        VoxelOctree::empty(Vec3::ZERO, Vec3::ONE).marching_cubes(&mut mesh);
+        assert!(mesh.is_empty());
+
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::ONE);
+        tree.set_voxel(Vec3::ZERO, 0, (1, 2, 3));
+        assert!(!assert_watertight(&tree).is_empty());
+    }
+}
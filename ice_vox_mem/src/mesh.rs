@@ -0,0 +1,765 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use glam::*;
+
+use crate::octree::{Color, NodeBounds, Octant, VoxelOctree};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeshMode {
+    /// One quad for every visible face of every leaf.
+    Culled,
+    /// Visible faces in the same plane with the same colour are merged into larger quads.
+    Greedy,
+    /// Smooth surface with shared vertices, extracted with marching cubes over the grid of
+    /// the finest leaves. Normals and colours are interpolated. Coarse leaves are sampled at
+    /// the finest depth too, so time and memory grow with the surface area measured in cells
+    /// of that depth, and a single small voxel makes every large face expensive.
+    MarchingCubes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeshFormat {
+    /// Wavefront OBJ with vertex colours and a companion .mtl.
+    Obj,
+    /// Binary glTF 2.0.
+    Glb,
+    /// Binary little endian PLY.
+    Ply,
+    /// Binary STL, without colours.
+    Stl,
+}
+
+impl MeshFormat {
+    /// Picks the format from the extension of `path`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "obj" => Some(MeshFormat::Obj),
+            "glb" => Some(MeshFormat::Glb),
+            "ply" => Some(MeshFormat::Ply),
+            "stl" => Some(MeshFormat::Stl),
+            _ => None,
+        }
+    }
+}
+
+/// Triangle mesh with per vertex attributes. Triangles are wound counter clockwise when seen
+/// from outside. The blocky mesh modes give every face its own vertices, so normals and
+/// colours stay flat.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub colors: Vec<Color>,
+    pub indices: Vec<u32>,
+}
+
+impl Mesh {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub(crate) fn push_vertex(&mut self, position: Vec3, normal: Vec3, color: Color) -> u32 {
+        self.positions.push(position);
+        self.normals.push(normal);
+        self.colors.push(color);
+        (self.positions.len() - 1) as u32
+    }
+
+    //Colour of every triangle is the colour of its first vertex
+    fn triangle_color(&self, tri: &[u32]) -> Color {
+        self.colors[tri[0] as usize]
+    }
+
+    //Triangles grouped by colour, with the colours in the order they first show up
+    fn materials(&self) -> Vec<(Color, Vec<&[u32]>)> {
+        let mut group_index = HashMap::new();
+        let mut groups: Vec<(Color, Vec<&[u32]>)> = Vec::new();
+        for tri in self.indices.chunks_exact(3) {
+            let color = self.triangle_color(tri);
+            let index = *group_index.entry(color).or_insert_with(|| {
+                groups.push((color, Vec::new()));
+                groups.len() - 1
+            });
+            groups[index].1.push(tri);
+        }
+        groups
+    }
+
+    /// Writes the mesh as a Wavefront OBJ, with vertex colours appended to the positions and
+    /// faces grouped by the materials from `write_mtl`. `mtllib` is the name of the file those
+    /// materials are written to. Without one the faces are still grouped by colour, but don't
+    /// reference any materials.
+    pub fn write_obj<W: Write>(&self, writer: W, mtllib: Option<&str>) -> io::Result<()> {
+        let mut w = BufWriter::new(writer);
+        if let Some(mtllib) = mtllib {
+            writeln!(w, "mtllib {}", mtllib)?;
+        }
+        for (p, &(r, g, b)) in self.positions.iter().zip(self.colors.iter()) {
+            writeln!(w, "v {} {} {} {:.4} {:.4} {:.4}", p.x, p.y, p.z, r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)?;
+        }
+
+        //Flat shaded meshes only have a handful of different normals, so share them
+        let mut normal_index: HashMap<[u32; 3], usize> = HashMap::new();
+        let mut vertex_normals = Vec::with_capacity(self.normals.len());
+        for n in self.normals.iter() {
+            let count = normal_index.len();
+            let index = *normal_index.entry([n.x.to_bits(), n.y.to_bits(), n.z.to_bits()]).or_insert(count);
+            if index == count {
+                writeln!(w, "vn {} {} {}", n.x, n.y, n.z)?;
+            }
+            vertex_normals.push(index);
+        }
+
+        //OBJ indices start at 1
+        for (color, triangles) in self.materials() {
+            if mtllib.is_some() {
+                writeln!(w, "usemtl {}", material_name(color))?;
+            }
+            for tri in triangles {
+                let [a, b, c] = [0, 1, 2].map(|i| tri[i] as usize);
+                writeln!(w, "f {}//{} {}//{} {}//{}", a + 1, vertex_normals[a] + 1, b + 1, vertex_normals[b] + 1, c + 1, vertex_normals[c] + 1)?;
+            }
+        }
+        w.flush()
+    }
+
+    /// Writes a Wavefront MTL with a diffuse material for every colour used in the mesh.
+    pub fn write_mtl<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut w = BufWriter::new(writer);
+        for (color, _) in self.materials() {
+            let (r, g, b) = color;
+            writeln!(w, "newmtl {}", material_name(color))?;
+            writeln!(w, "Kd {:.4} {:.4} {:.4}", r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)?;
+            writeln!(w)?;
+        }
+        w.flush()
+    }
+
+    /// Writes the mesh as a Wavefront OBJ to the file at `path` and its materials to a .mtl
+    /// file next to it, replacing them if they exist.
+    pub fn save_obj<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mtl_path = path.with_extension("mtl");
+        self.write_mtl(File::create(&mtl_path)?)?;
+        let mtllib = mtl_path.file_name().map(|name| name.to_string_lossy());
+        self.write_obj(File::create(path)?, mtllib.as_deref())
+    }
+
+    /// Writes the mesh as binary glTF 2.0, with normals and vertex colours.
+    pub fn write_glb<W: Write>(&self, writer: W) -> io::Result<()> {
+        //Binary chunk: positions, normals, colours and indices one after another. Every
+        //section is a multiple of 4 bytes long, so they all stay aligned
+        let mut bin: Vec<u8> = Vec::new();
+        for p in self.positions.iter() {
+            write_vec3(&mut bin, *p);
+        }
+        for n in self.normals.iter() {
+            write_vec3(&mut bin, *n);
+        }
+        //glTF vertex colours are linear, 16 bits keeps the dark end from banding
+        for &(r, g, b) in self.colors.iter() {
+            for c in [r, g, b] {
+                let linear = (srgb_to_linear(c) * 65535.0).round() as u16;
+                bin.extend_from_slice(&linear.to_le_bytes());
+            }
+            bin.extend_from_slice(&u16::MAX.to_le_bytes());
+        }
+        for i in self.indices.iter() {
+            bin.extend_from_slice(&i.to_le_bytes());
+        }
+
+        //The JSON chunk gets padded with spaces to stay aligned as well
+        let mut json = self.gltf_json(bin.len()).into_bytes();
+        json.resize((json.len() + 3) & !3, b' ');
+
+        let mut chunks = vec![(0x4E4F_534A_u32, &json)];
+        if !bin.is_empty() {
+            chunks.push((0x004E_4942_u32, &bin));
+        }
+        let length = 12 + chunks.iter().map(|(_, data)| 8 + data.len()).sum::<usize>();
+        let mut w = BufWriter::new(writer);
+        w.write_all(b"glTF")?;
+        w.write_all(&2u32.to_le_bytes())?;
+        w.write_all(&(length as u32).to_le_bytes())?;
+        for (kind, data) in chunks.iter() {
+            w.write_all(&(data.len() as u32).to_le_bytes())?;
+            w.write_all(&kind.to_le_bytes())?;
+            w.write_all(data)?;
+        }
+        w.flush()
+    }
+
+    fn gltf_json(&self, buffer_length: usize) -> String {
+        let asset = r#""asset":{"version":"2.0","generator":"ice_vox_mem"}"#;
+        //Accessors can't be empty, so an empty mesh is an empty scene
+        if self.is_empty() {
+            return format!(r#"{{{},"scene":0,"scenes":[{{}}]}}"#, asset);
+        }
+
+        let vertices = self.vertex_count();
+        let (min, max) = self.positions.iter().fold((Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)), |(min, max), &p| (min.min(p), max.max(p)));
+        let positions_length = vertices * 12;
+        let colors_offset = positions_length * 2;
+        let indices_offset = colors_offset + vertices * 8;
+
+        format!(concat!(
+            "{{{asset},",
+            r#""scene":0,"scenes":[{{"nodes":[0]}}],"nodes":[{{"mesh":0}}],"#,
+            r#""meshes":[{{"primitives":[{{"attributes":{{"POSITION":0,"NORMAL":1,"COLOR_0":2}},"indices":3,"material":0}}]}}],"#,
+            r#""materials":[{{"pbrMetallicRoughness":{{"baseColorFactor":[1,1,1,1],"metallicFactor":0,"roughnessFactor":1}}}}],"#,
+            r#""buffers":[{{"byteLength":{buffer_length}}}],"#,
+            r#""bufferViews":["#,
+            r#"{{"buffer":0,"byteOffset":0,"byteLength":{positions_length},"target":34962}},"#,
+            r#"{{"buffer":0,"byteOffset":{positions_length},"byteLength":{positions_length},"target":34962}},"#,
+            r#"{{"buffer":0,"byteOffset":{colors_offset},"byteLength":{colors_length},"target":34962}},"#,
+            r#"{{"buffer":0,"byteOffset":{indices_offset},"byteLength":{indices_length},"target":34963}}],"#,
+            r#""accessors":["#,
+            r#"{{"bufferView":0,"componentType":5126,"count":{vertices},"type":"VEC3","min":[{min_x},{min_y},{min_z}],"max":[{max_x},{max_y},{max_z}]}},"#,
+            r#"{{"bufferView":1,"componentType":5126,"count":{vertices},"type":"VEC3"}},"#,
+            r#"{{"bufferView":2,"componentType":5123,"normalized":true,"count":{vertices},"type":"VEC4"}},"#,
+            r#"{{"bufferView":3,"componentType":5125,"count":{indices},"type":"SCALAR"}}]}}"#),
+            asset = asset,
+            buffer_length = buffer_length,
+            positions_length = positions_length,
+            colors_offset = colors_offset,
+            colors_length = vertices * 8,
+            indices_offset = indices_offset,
+            indices_length = self.indices.len() * 4,
+            vertices = vertices,
+            indices = self.indices.len(),
+            min_x = min.x, min_y = min.y, min_z = min.z,
+            max_x = max.x, max_y = max.y, max_z = max.z,
+        )
+    }
+
+    /// Writes the mesh as binary little endian PLY, with normals and vertex colours.
+    pub fn write_ply<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut w = BufWriter::new(writer);
+        write!(w, concat!(
+            "ply\n",
+            "format binary_little_endian 1.0\n",
+            "comment ice_vox_mem\n",
+            "element vertex {}\n",
+            "property float x\nproperty float y\nproperty float z\n",
+            "property float nx\nproperty float ny\nproperty float nz\n",
+            "property uchar red\nproperty uchar green\nproperty uchar blue\n",
+            "element face {}\n",
+            "property list uchar uint vertex_indices\n",
+            "end_header\n"), self.vertex_count(), self.triangle_count())?;
+
+        let mut vertex = Vec::with_capacity(27);
+        for ((p, n), &(r, g, b)) in self.positions.iter().zip(self.normals.iter()).zip(self.colors.iter()) {
+            vertex.clear();
+            write_vec3(&mut vertex, *p);
+            write_vec3(&mut vertex, *n);
+            vertex.extend_from_slice(&[r, g, b]);
+            w.write_all(&vertex)?;
+        }
+        for tri in self.indices.chunks_exact(3) {
+            w.write_all(&[3])?;
+            for i in tri {
+                w.write_all(&i.to_le_bytes())?;
+            }
+        }
+        w.flush()
+    }
+
+    /// Writes the mesh as binary STL. STL has no standard way to store colours, so they're left out.
+    pub fn write_stl<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut w = BufWriter::new(writer);
+        let mut header = [0u8; 80];
+        let name = b"ice_vox_mem";
+        header[..name.len()].copy_from_slice(name);
+        w.write_all(&header)?;
+        w.write_all(&(self.triangle_count() as u32).to_le_bytes())?;
+
+        let mut facet = Vec::with_capacity(50);
+        for tri in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| self.positions[tri[i] as usize]);
+            facet.clear();
+            write_vec3(&mut facet, (b - a).cross(c - a).try_normalize().unwrap_or(Vec3::ZERO));
+            write_vec3(&mut facet, a);
+            write_vec3(&mut facet, b);
+            write_vec3(&mut facet, c);
+            facet.extend_from_slice(&0u16.to_le_bytes());
+            w.write_all(&facet)?;
+        }
+        w.flush()
+    }
+
+    /// Writes the mesh in the given format. OBJ is written without a material library, so
+    /// its colours only come from the vertices.
+    pub fn write<W: Write>(&self, writer: W, format: MeshFormat) -> io::Result<()> {
+        match format {
+            MeshFormat::Obj => self.write_obj(writer, None),
+            MeshFormat::Glb => self.write_glb(writer),
+            MeshFormat::Ply => self.write_ply(writer),
+            MeshFormat::Stl => self.write_stl(writer),
+        }
+    }
+
+    /// Writes the mesh to the file at `path` in the format matching its extension, replacing
+    /// it if it exists. OBJ files get their materials written next to them, see `save_obj`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        match MeshFormat::from_path(path) {
+            Some(MeshFormat::Obj) => self.save_obj(path),
+            Some(format) => self.write(File::create(path)?, format),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown mesh format for {}", path.display()))),
+        }
+    }
+}
+
+fn write_vec3(out: &mut Vec<u8>, v: Vec3) {
+    out.extend_from_slice(&v.x.to_le_bytes());
+    out.extend_from_slice(&v.y.to_le_bytes());
+    out.extend_from_slice(&v.z.to_le_bytes());
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn material_name((r, g, b): Color) -> String {
+    format!("color_{:02x}{:02x}{:02x}", r, g, b)
+}
+
+struct CubeFace {
+    axis: usize,
+    sign: f32,
+    //Corners relative to the center in units of half_size, counter clockwise seen from
+    //outside and triangulated as 0-1-2 and 2-3-0
+    corners: [[f32; 3]; 4],
+}
+
+const CUBE_FACES: [CubeFace; 6] = [
+    //Back
+    CubeFace { axis: 2, sign: -1.0, corners: [[-1.0, 1.0,-1.0], [ 1.0, 1.0,-1.0], [ 1.0,-1.0,-1.0], [-1.0,-1.0,-1.0]] },
+    //Front
+    CubeFace { axis: 2, sign:  1.0, corners: [[ 1.0,-1.0, 1.0], [ 1.0, 1.0, 1.0], [-1.0, 1.0, 1.0], [-1.0,-1.0, 1.0]] },
+    //Right
+    CubeFace { axis: 0, sign:  1.0, corners: [[ 1.0, 1.0,-1.0], [ 1.0, 1.0, 1.0], [ 1.0,-1.0, 1.0], [ 1.0,-1.0,-1.0]] },
+    //Left
+    CubeFace { axis: 0, sign: -1.0, corners: [[-1.0,-1.0,-1.0], [-1.0,-1.0, 1.0], [-1.0, 1.0, 1.0], [-1.0, 1.0,-1.0]] },
+    //Top
+    CubeFace { axis: 1, sign:  1.0, corners: [[-1.0, 1.0, 1.0], [ 1.0, 1.0, 1.0], [ 1.0, 1.0,-1.0], [-1.0, 1.0,-1.0]] },
+    //Bottom
+    CubeFace { axis: 1, sign: -1.0, corners: [[-1.0,-1.0,-1.0], [ 1.0,-1.0,-1.0], [ 1.0,-1.0, 1.0], [-1.0,-1.0, 1.0]] },
+];
+
+//Merged rectangle of visible faces, in cells of the finest leaf depth. The quad lies in the
+//plane `plane` along `axis`, spanning [u0, u1) x [v0, v1) on the two other axes.
+struct GreedyQuad {
+    axis: usize,
+    positive: bool,
+    plane: i64,
+    u0: i64,
+    v0: i64,
+    u1: i64,
+    v1: i64,
+    color: Color,
+}
+
+impl GreedyQuad {
+    fn point(&self, u: i64, v: i64) -> [i64; 3] {
+        let mut p = [0; 3];
+        p[self.axis] = self.plane;
+        p[(self.axis + 1) % 3] = u;
+        p[(self.axis + 2) % 3] = v;
+        p
+    }
+}
+
+//Visible square of a leaf face, in cells of the finest leaf depth. Faces that are partially
+//hidden are split into the squares of the neighbouring nodes that leave them open.
+pub(crate) struct VisibleFace {
+    pub u: i64,
+    pub v: i64,
+    pub size: i64,
+    pub color: Color,
+}
+
+//Visible faces by (axis, facing positive, plane)
+pub(crate) type FacePlanes = HashMap<(usize, bool, i64), Vec<VisibleFace>>;
+
+//Cells of one face size in a plane, row by row, in units of that size
+type FaceRows = BTreeMap<i64, BTreeMap<i64, Color>>;
+
+//Identifies the grid line through `p` running along `axis`
+fn line_key(axis: usize, p: [i64; 3]) -> (usize, i64, i64) {
+    (axis, p[(axis + 1) % 3], p[(axis + 2) % 3])
+}
+
+impl VoxelOctree {
+    /// Builds a mesh of the surface of the tree, leaving out faces between solid voxels.
+    pub fn build_mesh(&self, mode: MeshMode) -> Mesh {
+        let mut mesh = Mesh::new();
+        match mode {
+            MeshMode::Culled => self.culled_mesh(&self.root, self.bounds(), &mut mesh),
+            MeshMode::Greedy => self.greedy_mesh(&mut mesh),
+            MeshMode::MarchingCubes => self.marching_cubes(&mut mesh),
+        }
+
+        trace!("Vertices: {}", mesh.vertex_count());
+        trace!("Triangles: {}", mesh.triangle_count());
+        mesh
+    }
+
+    /// Builds a mesh and writes it to the file at `path`, in the format matching its
+    /// extension, see `Mesh::save`.
+    pub fn export_mesh<P: AsRef<Path>>(&self, path: P, mode: MeshMode) -> io::Result<()> {
+        self.build_mesh(mode).save(path)
+    }
+
+    //True if the face of a leaf is hidden behind solid neighbours, which can be coarser
+    //leaves or a group of finer ones that together cover the whole face
+    fn face_hidden(&self, bounds: NodeBounds, axis: usize, sign: f32) -> bool {
+        let mut neighbour_center = bounds.center;
+        neighbour_center[axis] += sign * bounds.half_size[axis] * 2.0;
+        match self.node_at(neighbour_center, bounds.depth) {
+            Some((neighbour, _)) => VoxelOctree::side_covered(neighbour, axis, -sign),
+            None => false,
+        }
+    }
+
+    fn culled_mesh(&self, octant: &Octant, bounds: NodeBounds, mesh: &mut Mesh) {
+        if octant.is_leaf() {
+            for face in CUBE_FACES.iter() {
+                if self.face_hidden(bounds, face.axis, face.sign) {
+                    continue;
+                }
+                let mut normal = Vec3::ZERO;
+                normal[face.axis] = face.sign;
+                let start = mesh.vertex_count() as u32;
+                for corner in face.corners.iter() {
+                    mesh.push_vertex(bounds.center + bounds.half_size * Vec3::from(*corner), normal, octant.color());
+                }
+                mesh.indices.extend_from_slice(&[start, start + 1, start + 2, start + 2, start + 3, start]);
+            }
+        }
+
+        for (i, child) in octant.children() {
+            self.culled_mesh(child, bounds.child(i), mesh);
+        }
+    }
+
+    //Depth of the finest leaves and the size of a cell at that depth
+    pub(crate) fn finest_grid(&self) -> Option<(u8, Vec3)> {
+        let max_depth = self.leaves().map(|(_, bounds)| bounds.depth).max()?;
+        Some((max_depth, self.half_size * 2.0 / (1u64 << max_depth) as f32))
+    }
+
+    //Collects the children of `octant` on its side facing `side` along `axis` that leave it
+    //open, descending into the ones that are partially filled
+    fn open_side(octant: &Octant, bounds: NodeBounds, axis: usize, side: f32, open: &mut Vec<NodeBounds>) {
+        for i in (0..8).filter(|&i| Octant::child_sign(i)[axis] == side) {
+            match octant.child(i) {
+                None => open.push(bounds.child(i)),
+                Some(child) if child.is_leaf() => {},
+                Some(child) => VoxelOctree::open_side(child, bounds.child(i), axis, side, open),
+            }
+        }
+    }
+
+    //Finds all visible faces, in cells of the finest leaf depth, and sorts them into planes.
+    //Faces keep the size of their leaf unless a partially filled neighbour hides some of it.
+    pub(crate) fn visible_faces(&self, unit: Vec3) -> FacePlanes {
+        let root_min = self.bounds().min();
+        let to_grid = |bounds: NodeBounds| {
+            let lo = ((bounds.min() - root_min) / unit).round();
+            ([lo.x as i64, lo.y as i64, lo.z as i64], (bounds.half_size.x * 2.0 / unit.x).round() as i64)
+        };
+        let mut planes: FacePlanes = HashMap::new();
+        let mut open = Vec::new();
+
+        for (octant, bounds) in self.leaves() {
+            let (lo, cells) = to_grid(bounds);
+            for &(axis, positive) in [(0, false), (0, true), (1, false), (1, true), (2, false), (2, true)].iter() {
+                let sign = if positive { 1.0 } else { -1.0 };
+                let mut neighbour_center = bounds.center;
+                neighbour_center[axis] += sign * bounds.half_size[axis] * 2.0;
+                open.clear();
+                match self.node_at(neighbour_center, bounds.depth) {
+                    Some((neighbour, _)) if neighbour.is_leaf() => continue,
+                    Some((neighbour, neighbour_bounds)) => VoxelOctree::open_side(neighbour, neighbour_bounds, axis, -sign, &mut open),
+                    None => open.push(bounds),
+                }
+
+                let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                let plane = if positive { lo[axis] + cells } else { lo[axis] };
+                let faces = planes.entry((axis, positive, plane)).or_default();
+                for part in open.iter() {
+                    let (part_lo, size) = to_grid(*part);
+                    faces.push(VisibleFace { u: part_lo[u], v: part_lo[v], size, color: octant.color() });
+                }
+            }
+        }
+        planes
+    }
+
+    fn greedy_quads(&self) -> (Vec<GreedyQuad>, Vec3) {
+        let unit = match self.finest_grid() {
+            Some((_, unit)) => unit,
+            None => return (Vec::new(), Vec3::ONE),
+        };
+
+        let mut quads = Vec::new();
+        for ((axis, positive, plane), faces) in self.visible_faces(unit) {
+            //Faces of different sizes are merged separately, each on the grid of its own size
+            let mut sizes: BTreeMap<i64, FaceRows> = BTreeMap::new();
+            for face in faces {
+                let rows = sizes.entry(face.size).or_default();
+                rows.entry(face.v / face.size).or_default().insert(face.u / face.size, face.color);
+            }
+
+            for (size, mut rows) in sizes {
+                //Grow the first unclaimed cell as far as possible along u, then along v
+                while let Some((v, u, color)) = rows.iter().next().and_then(|(&v, row)| row.iter().next().map(|(&u, &color)| (v, u, color))) {
+                    let mut w = 1;
+                    while rows[&v].get(&(u + w)) == Some(&color) {
+                        w += 1;
+                    }
+                    let mut h = 1;
+                    while let Some(row) = rows.get(&(v + h)) {
+                        if !(u..u + w).all(|x| row.get(&x) == Some(&color)) {
+                            break;
+                        }
+                        h += 1;
+                    }
+                    for y in v..v + h {
+                        let row = rows.get_mut(&y).unwrap();
+                        for x in u..u + w {
+                            row.remove(&x);
+                        }
+                        if row.is_empty() {
+                            rows.remove(&y);
+                        }
+                    }
+
+                    quads.push(GreedyQuad {
+                        axis,
+                        positive,
+                        plane,
+                        u0: u * size,
+                        v0: v * size,
+                        u1: (u + w) * size,
+                        v1: (v + h) * size,
+                        color,
+                    });
+                }
+            }
+        }
+        (quads, unit)
+    }
+
+    fn greedy_mesh(&self, mesh: &mut Mesh) {
+        let (quads, unit) = self.greedy_quads();
+        let root_min = self.bounds().min();
+
+        //Merged quads have corners in the middle of their neighbours' edges. To keep the
+        //mesh watertight those corners get inserted into the edges, so collect them per grid line
+        let mut lines: HashMap<(usize, i64, i64), Vec<i64>> = HashMap::new();
+        for quad in quads.iter() {
+            for &(u, v) in [(quad.u0, quad.v0), (quad.u1, quad.v0), (quad.u1, quad.v1), (quad.u0, quad.v1)].iter() {
+                let p = quad.point(u, v);
+                for axis in 0..3 {
+                    lines.entry(line_key(axis, p)).or_default().push(p[axis]);
+                }
+            }
+        }
+        for line in lines.values_mut() {
+            line.sort_unstable();
+            line.dedup();
+        }
+
+        let to_world = |p: [f64; 3]| root_min + vec3(p[0] as f32, p[1] as f32, p[2] as f32) * unit;
+
+        for quad in quads.iter() {
+            let (u_axis, v_axis) = ((quad.axis + 1) % 3, (quad.axis + 2) % 3);
+            let corners = [(quad.u0, quad.v0), (quad.u1, quad.v0), (quad.u1, quad.v1), (quad.u0, quad.v1)];
+
+            //Walk the outline counter clockwise around +axis, picking up points on the edges
+            let mut outline: Vec<[i64; 3]> = Vec::with_capacity(4);
+            let mut is_corner: Vec<bool> = Vec::with_capacity(4);
+            for c in 0..4 {
+                let from = quad.point(corners[c].0, corners[c].1);
+                let to = quad.point(corners[(c + 1) % 4].0, corners[(c + 1) % 4].1);
+                outline.push(from);
+                is_corner.push(true);
+                let edge_axis = if c % 2 == 0 { u_axis } else { v_axis };
+                let (lo, hi) = (from[edge_axis].min(to[edge_axis]), from[edge_axis].max(to[edge_axis]));
+                let mut between: Vec<i64> = lines[&line_key(edge_axis, from)].iter().cloned().filter(|&t| t > lo && t < hi).collect();
+                if from[edge_axis] > to[edge_axis] {
+                    between.reverse();
+                }
+                for t in between {
+                    let mut p = from;
+                    p[edge_axis] = t;
+                    outline.push(p);
+                    is_corner.push(false);
+                }
+            }
+            //Faces looking down -axis need the opposite winding to be counter clockwise from outside
+            if !quad.positive {
+                outline.reverse();
+                is_corner.reverse();
+            }
+
+            let mut normal = Vec3::ZERO;
+            normal[quad.axis] = if quad.positive { 1.0 } else { -1.0 };
+            let n = outline.len();
+            let outline: Vec<u32> = outline.iter().map(|p| mesh.push_vertex(to_world([p[0] as f64, p[1] as f64, p[2] as f64]), normal, quad.color)).collect();
+            //A fan from a corner works as long as neither of its edges got split, otherwise it
+            //would produce zero area triangles along that edge
+            let apex = (0..n).find(|&i| is_corner[i] && is_corner[(i + 1) % n] && is_corner[(i + n - 1) % n]);
+            if let Some(apex) = apex {
+                for i in 1..n - 1 {
+                    mesh.indices.extend_from_slice(&[outline[apex], outline[(apex + i) % n], outline[(apex + i + 1) % n]]);
+                }
+            } else {
+                //Fan around the center instead
+                let mut center = [0.0; 3];
+                center[quad.axis] = quad.plane as f64;
+                center[u_axis] = (quad.u0 + quad.u1) as f64 / 2.0;
+                center[v_axis] = (quad.v0 + quad.v1) as f64 / 2.0;
+                let center = mesh.push_vertex(to_world(center), normal, quad.color);
+                for i in 0..outline.len() {
+                    mesh.indices.extend_from_slice(&[center, outline[i], outline[(i + 1) % outline.len()]]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::octree::tests::random_tree;
+
+    //Every visible face of a cell of the finest grid, as (axis, positive, plane, u, v, colour)
+    type CellFaces = HashSet<(usize, bool, i64, i64, i64, Color)>;
+
+    fn brute_force_faces(tree: &VoxelOctree) -> CellFaces {
+        let mut faces = HashSet::new();
+        let (depth, unit) = match tree.finest_grid() {
+            Some(grid) => grid,
+            None => return faces,
+        };
+        let cells = 1i64 << depth;
+        let cell = |p: [i64; 3]| {
+            let center = tree.bounds().min() + (vec3(p[0] as f32, p[1] as f32, p[2] as f32) + Vec3::splat(0.5)) * unit;
+            tree.get_voxel(center).map(|info| info.color)
+        };
+        for i in 0..cells * cells * cells {
+            let p = [i / (cells * cells), i / cells % cells, i % cells];
+            let color = match cell(p) {
+                Some(color) => color,
+                None => continue,
+            };
+            for axis in 0..3 {
+                for &positive in [false, true].iter() {
+                    let mut neighbour = p;
+                    neighbour[axis] += if positive { 1 } else { -1 };
+                    if cell(neighbour).is_none() {
+                        let plane = if positive { p[axis] + 1 } else { p[axis] };
+                        faces.insert((axis, positive, plane, p[(axis + 1) % 3], p[(axis + 2) % 3], color));
+                    }
+                }
+            }
+        }
+        faces
+    }
+
+    #[test]
+    fn greedy_quads_cover_visible_faces() {
+        for seed in 0..20 {
+            let tree = random_tree(seed);
+            let (quads, _) = tree.greedy_quads();
+            let mut faces = HashSet::new();
+            for quad in quads.iter() {
+                for u in quad.u0..quad.u1 {
+                    for v in quad.v0..quad.v1 {
+                        assert!(faces.insert((quad.axis, quad.positive, quad.plane, u, v, quad.color)), "overlapping quads, seed {}", seed);
+                    }
+                }
+            }
+            assert_eq!(faces, brute_force_faces(&tree), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn greedy_mesh_is_watertight() {
+        for seed in 0..20 {
+            let mesh = random_tree(seed).build_mesh(MeshMode::Greedy);
+            //Every edge has to be shared with a triangle running along it the other way
+            let key = |p: Vec3| [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+            let mut edges: HashMap<([u32; 3], [u32; 3]), i32> = HashMap::new();
+            for tri in mesh.indices.chunks_exact(3) {
+                for i in 0..3 {
+                    let (a, b) = (key(mesh.positions[tri[i] as usize]), key(mesh.positions[tri[(i + 1) % 3] as usize]));
+                    *edges.entry((a, b)).or_default() += 1;
+                    *edges.entry((b, a)).or_default() -= 1;
+                }
+            }
+            assert!(edges.values().all(|&count| count == 0), "open edges, seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn greedy_mixed_depths() {
+        //A coarse leaf next to a single voxel 10 levels finer
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(2048.0));
+        tree.set_voxel(Vec3::splat(-1.0), 1, (1, 1, 1));
+        tree.set_voxel(Vec3::splat(0.5), 11, (2, 2, 2));
+        let (quads, _) = tree.greedy_quads();
+        assert_eq!(quads.len(), 12);
+    }
+
+    #[test]
+    fn obj_materials_need_a_library() {
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(2.0));
+        tree.root.set_child(0, Some(Octant::leaf(200, 0, 0)));
+        tree.root.set_child(7, Some(Octant::leaf(0, 200, 0)));
+        tree.update_lod();
+        let mesh = tree.build_mesh(MeshMode::Culled);
+        let obj = |mtllib| {
+            let mut out = Vec::new();
+            mesh.write_obj(&mut out, mtllib).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let with_library = obj(Some("mesh.mtl"));
+        assert!(with_library.starts_with("mtllib mesh.mtl\n"));
+        assert_eq!(with_library.lines().filter(|line| line.starts_with("usemtl ")).count(), 2);
+
+        let mut written = Vec::new();
+        mesh.write(&mut written, MeshFormat::Obj).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert_eq!(written, obj(None));
+        assert!(!written.contains("mtllib") && !written.contains("usemtl"));
+        assert_eq!(written.lines().filter(|line| line.starts_with("f ")).count(), mesh.triangle_count());
+    }
+}
@@ -0,0 +1,188 @@
+// Seeded gradient noise for procedural generation.
+//
+// Both Perlin variants use unit length gradients, which keeps their output within [-1, 1]
+// and lets `LIPSCHITZ_2D`/`LIPSCHITZ_3D` bound how fast they can change. Generators use
+// those bounds to decide whole octants without sampling every voxel in them.
+
+use std::f32::consts::{FRAC_1_SQRT_2, SQRT_2};
+
+use glam::*;
+
+/// Upper bound on the gradient length of `Perlin::noise2`.
+//Per axis the fade weights change by at most 2 * 15/8 times a corner offset of at most
+//sqrt(2), plus 1 for the blended gradients themselves
+pub const LIPSCHITZ_2D: f32 = SQRT_2 * (2.0 * 1.875 * SQRT_2 + 1.0);
+/// Upper bound on the gradient length of `Perlin::noise3`.
+pub const LIPSCHITZ_3D: f32 = 1.732_051 * (2.0 * 1.875 * 1.732_051 + 1.0);
+
+const D: f32 = FRAC_1_SQRT_2;
+
+const GRADIENTS_2D: [[f32; 2]; 8] = [
+    [1.0, 0.0], [-1.0, 0.0], [0.0, 1.0], [0.0, -1.0],
+    [D, D], [-D, D], [D, -D], [-D, -D],
+];
+
+//The 12 cube edge directions of improved Perlin noise, normalized
+const GRADIENTS_3D: [[f32; 3]; 12] = [
+    [D, D, 0.0], [-D, D, 0.0], [D, -D, 0.0], [-D, -D, 0.0],
+    [D, 0.0, D], [-D, 0.0, D], [D, 0.0, -D], [-D, 0.0, -D],
+    [0.0, D, D], [0.0, -D, D], [0.0, D, -D], [0.0, -D, -D],
+];
+
+//splitmix64, only used to shuffle the permutation table
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Classic Perlin gradient noise with a permutation table shuffled from a seed.
+pub struct Perlin {
+    perm: [u8; 512],
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Self {
+        let mut table = [0u8; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = i as u8;
+        }
+        let mut state = seed;
+        for i in (1..256).rev() {
+            let j = (next_random(&mut state) % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+
+        let mut perm = [0u8; 512];
+        for i in 0..512 {
+            perm[i] = table[i & 255];
+        }
+        Self { perm }
+    }
+
+    fn hash(&self, x: i32, y: i32) -> usize {
+        self.perm[self.perm[(x & 255) as usize] as usize + (y & 255) as usize] as usize
+    }
+
+    fn hash3(&self, x: i32, y: i32, z: i32) -> usize {
+        self.perm[self.hash(x, y) + (z & 255) as usize] as usize
+    }
+
+    /// 2D noise in [-1, 1], repeating every 256 units.
+    pub fn noise2(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (ix, iy) = (x0 as i32, y0 as i32);
+
+        let corner = |cx: i32, cy: i32| {
+            let g = GRADIENTS_2D[self.hash(ix + cx, iy + cy) & 7];
+            g[0] * (fx - cx as f32) + g[1] * (fy - cy as f32)
+        };
+        let (u, v) = (fade(fx), fade(fy));
+        lerp(lerp(corner(0, 0), corner(1, 0), u), lerp(corner(0, 1), corner(1, 1), u), v)
+    }
+
+    /// 3D noise in [-1, 1], repeating every 256 units.
+    pub fn noise3(&self, p: Vec3) -> f32 {
+        let p0 = p.floor();
+        let f = p - p0;
+        let (ix, iy, iz) = (p0.x as i32, p0.y as i32, p0.z as i32);
+
+        let corner = |cx: i32, cy: i32, cz: i32| {
+            let g = GRADIENTS_3D[self.hash3(ix + cx, iy + cy, iz + cz) % 12];
+            g[0] * (f.x - cx as f32) + g[1] * (f.y - cy as f32) + g[2] * (f.z - cz as f32)
+        };
+        let (u, v, w) = (fade(f.x), fade(f.y), fade(f.z));
+        let z0 = lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), u), lerp(corner(0, 1, 0), corner(1, 1, 0), u), v);
+        let z1 = lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), u), lerp(corner(0, 1, 1), corner(1, 1, 1), u), v);
+        lerp(z0, z1, w)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FbmParams {
+    pub seed: u64,
+    /// Number of noise layers added together.
+    pub octaves: u32,
+    /// Frequency of the first layer, in cycles per world unit.
+    pub frequency: f32,
+    /// Frequency multiplier from one layer to the next.
+    pub lacunarity: f32,
+    /// Amplitude multiplier from one layer to the next.
+    pub gain: f32,
+}
+
+impl Default for FbmParams {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            octaves: 5,
+            frequency: 1.0 / 64.0,
+            lacunarity: 2.0,
+            gain: 0.5,
+        }
+    }
+}
+
+/// Fractal Brownian motion: layers of Perlin noise at increasing frequency and decreasing
+/// amplitude, normalized back to [-1, 1].
+pub struct Fbm {
+    pub params: FbmParams,
+    perlin: Perlin,
+}
+
+impl Fbm {
+    pub fn new(params: FbmParams) -> Self {
+        Self {
+            params,
+            perlin: Perlin::new(params.seed),
+        }
+    }
+
+    //Frequency and amplitude of every layer, with the amplitudes adding up to 1
+    fn layers(&self) -> impl Iterator<Item = (usize, f32, f32)> {
+        let params = self.params;
+        let total: f32 = (0..params.octaves).map(|i| params.gain.powi(i as i32)).sum();
+        (0..params.octaves as usize).map(move |i| {
+            (i, params.frequency * params.lacunarity.powi(i as i32), params.gain.powi(i as i32) / total)
+        })
+    }
+
+    //Every layer samples a different part of the noise, so they don't line up at the origin
+    fn layer_offset(i: usize) -> f32 {
+        i as f32 * 31.416
+    }
+
+    pub fn sample2(&self, x: f32, y: f32) -> f32 {
+        self.layers().map(|(i, frequency, amplitude)| {
+            let offset = Fbm::layer_offset(i);
+            amplitude * self.perlin.noise2(x * frequency + offset, y * frequency + offset)
+        }).sum()
+    }
+
+    pub fn sample3(&self, p: Vec3) -> f32 {
+        self.layers().map(|(i, frequency, amplitude)| {
+            amplitude * self.perlin.noise3(p * frequency + Vec3::splat(Fbm::layer_offset(i)))
+        }).sum()
+    }
+
+    /// Upper bound on how fast `sample2` changes per world unit.
+    pub fn lipschitz2(&self) -> f32 {
+        self.layers().map(|(_, frequency, amplitude)| amplitude * frequency * LIPSCHITZ_2D).sum()
+    }
+
+    /// Upper bound on how fast `sample3` changes per world unit.
+    pub fn lipschitz3(&self) -> f32 {
+        self.layers().map(|(_, frequency, amplitude)| amplitude * frequency * LIPSCHITZ_3D).sum()
+    }
+}
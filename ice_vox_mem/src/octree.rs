@@ -1,127 +1,738 @@
 use glam::*;
 
+pub type Color = (u8, u8, u8);
+
+//Colour used when a generator has no opinion, bright enough to stand out
+pub const DEFAULT_COLOR: Color = (255, 0, 255);
+
+#[derive(Clone)]
 pub struct Octant {
     // Data layout:
     // [  0-7] bool is_leaf
     // [ 8-15] u8 r
     // [16-23] u8 g
     // [24-31] u8 b
-    pub data: u32,
-
-    // TODO: An option stores 4 extra bytes in a few cases, needs to be tested.
-    // We use Option<&Octant> here instead of a pointer, because it's a lot safer.
-    pub children: [Option<Box<Octant>>; 8],
+    // [32-39] u8 child mask, bit `i` is set when child `i` is occupied
+    // [40-47] u8 coverage, how much of the octant is solid from 0 to 255
+    // Interior nodes store the average colour of their children, weighted by coverage, so the
+    // tree can be drawn or queried at a coarser depth. See `Octant::update_lod`.
+    pub data: u64,
 
-    // Node position, size and depth
-    // TODO: this can be calculated implicitly, might be worth the memory saving?
-    pub center: Vec3,
-    pub half_size: Vec3,
-    pub depth: u8,
+    // Children are stored at their octant index, see `NodeBounds::child_index`. Unoccupied
+    // slots hold an empty octant, and nodes without children don't allocate the array at all.
+    // Node position, size and depth are not stored, they're derived from the root bounds
+    // while walking the tree (see `NodeBounds`), so an octant is only 16 bytes.
+    pub children: Option<Box<[Octant; 8]>>,
 }
 
+const CHILD_MASK_SHIFT: u64 = 32;
+const COVERAGE_SHIFT: u64 = 40;
+
 impl Octant {
-    pub fn leaf(center: Vec3, half_size: Vec3, depth: u8, r: u8, g: u8, b: u8) -> Self {
-        let mut data: u32 = 0;
-        data |= true as u32;
-        data |= (r as u32) << 8;
-        data |= (g as u32) << 16;
-        data |= (b as u32) << 24;
+    pub fn leaf(r: u8, g: u8, b: u8) -> Self {
+        let mut data: u64 = 0;
+        data |= true as u64;
+        data |= (r as u64) << 8;
+        data |= (g as u64) << 16;
+        data |= (b as u64) << 24;
+        data |= 0xFF << COVERAGE_SHIFT;
         Self {
-            data: data,
-            children: [None, None, None, None, None, None, None, None],
-            center: center,
-            half_size: half_size,
-            depth: depth,
+            data,
+            children: None,
         }
     }
 
-    pub fn empty(center: Vec3, half_size: Vec3, depth: u8) -> Self {
+    pub fn empty() -> Self {
         Self {
             data: 0,
-            children: [None, None, None, None, None, None, None, None],
-            center: center,
-            half_size: half_size,
-            depth: depth,
+            children: None,
         }
     }
 
     pub fn set_leaf(&mut self, leaf: bool) {
-        self.data = self.data & (!0xFFu32);
-        self.data |= leaf as u32;
+        self.data &= !0xFFu64;
+        self.data |= leaf as u64;
     }
 
     pub fn is_leaf(&self) -> bool {
         (self.data & 0xFF) != 0
     }
 
-    pub fn color(&self) -> (u8, u8, u8) {
+    pub fn color(&self) -> Color {
         let r = (self.data & (0xFF << 8)) >> 8;
         let g = (self.data & (0xFF << 16)) >> 16;
         let b = (self.data & (0xFF << 24)) >> 24;
         (r as u8, g as u8, b as u8)
     }
+
+    /// How much of the octant is solid, 255 for leaves and 0 for empty nodes.
+    pub fn coverage(&self) -> u8 {
+        ((self.data >> COVERAGE_SHIFT) & 0xFF) as u8
+    }
+
+    /// Recomputes the colour and coverage of an interior node from its children, which
+    /// should be up to date already. Does nothing for leaves.
+    pub fn update_lod(&mut self) {
+        if self.is_leaf() {
+            return;
+        }
+        let mut total = 0u64;
+        let mut sum = [0u64; 3];
+        for (_, child) in self.children() {
+            let weight = child.coverage() as u64;
+            let (r, g, b) = child.color();
+            total += weight;
+            sum[0] += r as u64 * weight;
+            sum[1] += g as u64 * weight;
+            sum[2] += b as u64 * weight;
+        }
+
+        //Rounded up, so anything with a solid voxel in it has some coverage
+        let coverage = total.div_ceil(8);
+        self.data &= (0xFF << CHILD_MASK_SHIFT) | 0xFF;
+        self.data |= coverage << COVERAGE_SHIFT;
+        for (i, channel) in sum.iter().enumerate() {
+            if let Some(average) = (channel + total / 2).checked_div(total) {
+                self.data |= average << (8 * (i + 1));
+            }
+        }
+    }
+
+    pub fn child_mask(&self) -> u8 {
+        ((self.data >> CHILD_MASK_SHIFT) & 0xFF) as u8
+    }
+
+    pub fn has_child(&self, i: usize) -> bool {
+        self.child_mask() & (1 << i) != 0
+    }
+
+    pub fn child(&self, i: usize) -> Option<&Octant> {
+        match &self.children {
+            Some(children) if self.has_child(i) => Some(&children[i]),
+            _ => None,
+        }
+    }
+
+    pub fn child_mut(&mut self, i: usize) -> Option<&mut Octant> {
+        let occupied = self.has_child(i);
+        match &mut self.children {
+            Some(children) if occupied => Some(&mut children[i]),
+            _ => None,
+        }
+    }
+
+    /// Stores `child` at octant index `i`, keeping the child mask in sync.
+    pub fn set_child(&mut self, i: usize, child: Option<Octant>) {
+        match child {
+            Some(child) => {
+                self.data |= 1 << (CHILD_MASK_SHIFT + i as u64);
+                let children = self.children.get_or_insert_with(|| Box::new([
+                    Octant::empty(), Octant::empty(), Octant::empty(), Octant::empty(),
+                    Octant::empty(), Octant::empty(), Octant::empty(), Octant::empty(),
+                ]));
+                children[i] = child;
+            },
+            None => {
+                self.data &= !(1 << (CHILD_MASK_SHIFT + i as u64));
+                if self.child_mask() == 0 {
+                    self.children = None;
+                } else if let Some(children) = &mut self.children {
+                    children[i] = Octant::empty();
+                }
+            },
+        }
+    }
+
+    /// Iterates over the occupied children together with their octant index.
+    pub fn children(&self) -> impl Iterator<Item = (usize, &Octant)> {
+        (0..8).filter_map(move |i| self.child(i).map(|c| (i, c)))
+    }
+
+    /// Direction (-1 or 1 per axis) from the parent center to the child at octant index `i`.
+    pub fn child_sign(i: usize) -> Vec3 {
+        let x = ((i >> 2) & 1) as f32 * 2.0 - 1.0;
+        let y = ((i >> 1) & 1) as f32 * 2.0 - 1.0;
+        let z = (i & 1) as f32 * 2.0 - 1.0;
+        vec3(x,y,z)
+    }
+
+    //Turns a leaf into an interior node with 8 leaf children of the same colour
+    pub(crate) fn split(&mut self) {
+        let (r, g, b) = self.color();
+        *self = Octant::empty();
+        for i in 0..8 {
+            self.set_child(i, Some(Octant::leaf(r,g,b)));
+        }
+        self.update_lod();
+    }
+
+    fn info(&self, bounds: NodeBounds) -> VoxelInfo {
+        VoxelInfo {
+            color: self.color(),
+            depth: bounds.depth,
+            center: bounds.center,
+            half_size: bounds.half_size,
+            is_leaf: self.is_leaf(),
+            coverage: self.coverage() as f32 / 255.0,
+        }
+    }
+}
+
+/// Position, size and depth of a node, computed while walking down from the root.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeBounds {
+    pub center: Vec3,
+    pub half_size: Vec3,
+    pub depth: u8,
+}
+
+impl NodeBounds {
+    pub fn min(&self) -> Vec3 {
+        self.center - self.half_size
+    }
+
+    pub fn max(&self) -> Vec3 {
+        self.center + self.half_size
+    }
+
+    /// Distance from `pos` to the closest point of the node, 0 inside it.
+    pub fn distance(&self, pos: Vec3) -> f32 {
+        (pos - pos.max(self.min()).min(self.max())).length()
+    }
+
+    pub fn contains(&self, pos: Vec3) -> bool {
+        let d = (pos - self.center).abs();
+        d.x <= self.half_size.x && d.y <= self.half_size.y && d.z <= self.half_size.z
+    }
+
+    /// Octant index of the child containing `pos`: bit 2 is set for +x, bit 1 for +y and bit 0 for +z.
+    pub fn child_index(&self, pos: Vec3) -> usize {
+        let dir = pos - self.center;
+        ((dir.x >= 0.0) as usize) << 2 | ((dir.y >= 0.0) as usize) << 1 | (dir.z >= 0.0) as usize
+    }
+
+    pub fn child(&self, i: usize) -> NodeBounds {
+        NodeBounds {
+            center: self.center + self.half_size * Octant::child_sign(i) * 0.5,
+            half_size: self.half_size / 2.0,
+            depth: self.depth + 1,
+        }
+    }
+}
+
+/// Result of a point query on a `VoxelOctree`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoxelInfo {
+    pub color: Color,
+    pub depth: u8,
+    pub center: Vec3,
+    pub half_size: Vec3,
+    /// False when the query stopped at an interior node because of the depth limit.
+    pub is_leaf: bool,
+    /// How much of the node is solid, 1 for leaves.
+    pub coverage: f32,
+}
+
+/// Result of a raycast against a `VoxelOctree`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayHit {
+    /// Distance along the (normalized) ray direction.
+    pub distance: f32,
+    pub position: Vec3,
+    /// Normal of the face the ray entered through.
+    pub normal: Vec3,
+    pub color: Color,
+    pub depth: u8,
+    /// How much of the node that was hit is solid, below 1 when a LOD raycast stopped early.
+    pub coverage: f32,
+}
+
+/// How far down the LOD queries of a `VoxelOctree` descend. They stop at leaves or at the
+/// first node that is coarse enough, and report its averaged colour.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lod {
+    /// Stop at this depth.
+    Depth(u8),
+    /// Stop at nodes that look smaller than this from the viewer, as their size divided by
+    /// their distance. That's roughly the angle they cover in radians, so for example the
+    /// field of view divided by the screen height stops at nodes about a pixel big.
+    ProjectedSize(f32),
+}
+
+impl Lod {
+    //True if a node seen from `distance` away doesn't have to be subdivided any further
+    fn stops_at(&self, bounds: NodeBounds, distance: f32) -> bool {
+        match *self {
+            Lod::Depth(depth) => bounds.depth >= depth,
+            Lod::ProjectedSize(size) => bounds.half_size.max_element() * 2.0 < size * distance,
+        }
+    }
+}
+
+//Slab test against an axis aligned box, returns the entry and exit distance along the ray
+//together with the axis the ray entered through
+fn ray_box(center: Vec3, half_size: Vec3, origin: Vec3, inv_dir: Vec3) -> (f32, f32, usize) {
+    let mut t_near = f32::NEG_INFINITY;
+    let mut t_far = f32::INFINITY;
+    let mut axis = 0;
+    for i in 0..3 {
+        let min = center[i] - half_size[i];
+        let max = center[i] + half_size[i];
+        if inv_dir[i].is_infinite() {
+            //The ray runs parallel to this slab, so it's either always or never inside it
+            if origin[i] < min || origin[i] > max {
+                return (f32::INFINITY, f32::NEG_INFINITY, 0);
+            }
+            continue;
+        }
+        let t0 = (min - origin[i]) * inv_dir[i];
+        let t1 = (max - origin[i]) * inv_dir[i];
+        if t0.min(t1) > t_near {
+            t_near = t0.min(t1);
+            axis = i;
+        }
+        t_far = t_far.min(t0.max(t1));
+    }
+    (t_near, t_far, axis)
+}
+
+//Node of any of the tree layouts, for walking it with `raycast_tree`
+pub(crate) trait RayNode: Sized {
+    //Colour and coverage when the ray stops at this node, None to look at its children
+    fn hit(&self, bounds: NodeBounds, distance: f32) -> Option<(Color, f32)>;
+    fn child(&self, i: usize) -> Option<Self>;
+}
+
+//Casts a ray through a tree starting at `root`, returning the first node it stops at
+//within `max_dist`
+pub(crate) fn raycast_tree<N: RayNode>(root: N, bounds: NodeBounds, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<RayHit> {
+    let dir = dir.try_normalize()?;
+    let inv_dir = dir.recip();
+    raycast_node(&root, bounds, origin, dir, inv_dir, max_dist)
+}
+
+fn raycast_node<N: RayNode>(node: &N, bounds: NodeBounds, origin: Vec3, dir: Vec3, inv_dir: Vec3, max_dist: f32) -> Option<RayHit> {
+    let (t_near, t_far, axis) = ray_box(bounds.center, bounds.half_size, origin, inv_dir);
+    if t_near > t_far || t_far < 0.0 || t_near > max_dist {
+        return None;
+    }
+
+    let distance = t_near.max(0.0);
+    if let Some((color, coverage)) = node.hit(bounds, distance) {
+        let mut normal = Vec3::ZERO;
+        normal[axis] = -dir[axis].signum();
+        return Some(RayHit {
+            distance,
+            position: origin + dir * distance,
+            normal,
+            color,
+            depth: bounds.depth,
+            coverage,
+        });
+    }
+
+    //Visit the children in the order the ray enters them, so the first hit is the closest
+    let mut order: Vec<(f32, N, NodeBounds)> = Vec::with_capacity(8);
+    for i in 0..8 {
+        if let Some(child) = node.child(i) {
+            let child_bounds = bounds.child(i);
+            let (child_near, child_far, _) = ray_box(child_bounds.center, child_bounds.half_size, origin, inv_dir);
+            if child_near <= child_far && child_far >= 0.0 && child_near <= max_dist {
+                order.push((child_near, child, child_bounds));
+            }
+        }
+    }
+    order.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    order.into_iter().find_map(|(_, child, child_bounds)| {
+        raycast_node(&child, child_bounds, origin, dir, inv_dir, max_dist)
+    })
 }
 
-#[derive(PartialEq)]
+//Octant seen through a LOD limit, skipping empty subtrees
+struct LodOctant<'a> {
+    octant: &'a Octant,
+    lod: Lod,
+}
+
+impl<'a> RayNode for LodOctant<'a> {
+    fn hit(&self, bounds: NodeBounds, distance: f32) -> Option<(Color, f32)> {
+        if self.octant.is_leaf() || self.lod.stops_at(bounds, distance) {
+            Some((self.octant.color(), self.octant.coverage() as f32 / 255.0))
+        } else {
+            None
+        }
+    }
+
+    fn child(&self, i: usize) -> Option<Self> {
+        let child = self.octant.child(i).filter(|child| child.coverage() != 0)?;
+        Some(LodOctant { octant: child, lod: self.lod })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OctantFillState {
     Empty,
-    ContainsVoxel,
-    Full,
+    /// The octant is partially filled and gets subdivided further. The colour is
+    /// used if it ends up as a leaf because the max depth was reached.
+    ContainsVoxel(Color),
+    /// The whole octant is filled with a single colour and becomes a leaf.
+    Full(Color),
 }
 
+/// True if the octant between `inner` and `outer` is as small as octants get, with
+/// `leaf_size` the size of the voxels at max depth. Classifiers should decide those exactly,
+/// usually by sampling their center.
+pub fn is_leaf_octant(inner: Vec3, outer: Vec3, leaf_size: Vec3) -> bool {
+    //A little slack for rounding in the octant bounds
+    (outer - inner).abs().max_element() <= leaf_size.max_element() * 1.02
+}
+
+/// Combines two classifiers for `VoxelOctree::generate`, filling what `fill` fills except
+/// for what `carve` fills. Both should decide the smallest octants exactly by returning
+/// `Full` or `Empty` for them, since an octant both call partially filled ends up solid.
+pub fn carved<F, C>(fill: F, carve: C) -> impl Fn(Vec3, Vec3, Vec3) -> OctantFillState + Copy
+where
+    F: Fn(Vec3, Vec3, Vec3) -> OctantFillState + Copy,
+    C: Fn(Vec3, Vec3, Vec3) -> OctantFillState + Copy,
+{
+    move |center, inner, outer| {
+        match fill(center, inner, outer) {
+            OctantFillState::Empty => OctantFillState::Empty,
+            filled => match carve(center, inner, outer) {
+                OctantFillState::Empty => filled,
+                OctantFillState::Full(_) => OctantFillState::Empty,
+                OctantFillState::ContainsVoxel(_) => match filled {
+                    OctantFillState::Full(color) | OctantFillState::ContainsVoxel(color) => OctantFillState::ContainsVoxel(color),
+                    OctantFillState::Empty => OctantFillState::Empty,
+                },
+            },
+        }
+    }
+}
+
+/// Deepest level `VoxelOctree::generate` and `VoxelOctree::carve` subdivide to, deeper
+/// octants are too small to tell apart with f32 coordinates.
+pub const MAX_GENERATE_DEPTH: u8 = 24;
+
 pub struct VoxelOctree {
     pub root: Octant,
+    pub center: Vec3,
+    pub half_size: Vec3,
+}
+
+/// Depth-first iterator over the leaves of a `VoxelOctree`, see `VoxelOctree::leaves`.
+pub struct Leaves<'a> {
+    stack: Vec<(&'a Octant, NodeBounds)>,
+}
+
+impl<'a> Iterator for Leaves<'a> {
+    type Item = (&'a Octant, NodeBounds);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((octant, bounds)) = self.stack.pop() {
+            if octant.is_leaf() {
+                return Some((octant, bounds));
+            }
+            for (i, child) in octant.children() {
+                self.stack.push((child, bounds.child(i)));
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over the leaves of a `VoxelOctree` that overlap a box, see `VoxelOctree::query_aabb`.
+pub struct AabbLeaves<'a> {
+    stack: Vec<(&'a Octant, NodeBounds)>,
+    min: Vec3,
+    max: Vec3,
+}
+
+impl<'a> AabbLeaves<'a> {
+    fn overlaps(&self, bounds: NodeBounds) -> bool {
+        let (min, max) = (bounds.min(), bounds.max());
+        (0..3).all(|i| {
+            if self.min[i] == self.max[i] {
+                //A flat box has no inside along this axis, so it takes what it touches
+                min[i] <= self.min[i] && self.max[i] <= max[i]
+            } else {
+                min[i] < self.max[i] && self.min[i] < max[i]
+            }
+        })
+    }
+}
+
+impl<'a> Iterator for AabbLeaves<'a> {
+    type Item = (&'a Octant, NodeBounds);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((octant, bounds)) = self.stack.pop() {
+            if octant.is_leaf() {
+                return Some((octant, bounds));
+            }
+            for (i, child) in octant.children() {
+                let child_bounds = bounds.child(i);
+                if self.overlaps(child_bounds) {
+                    self.stack.push((child, child_bounds));
+                }
+            }
+        }
+        None
+    }
 }
 
 impl VoxelOctree {
     pub fn empty(center: Vec3, size: Vec3) -> Self {
-        let root = Octant::empty(center, size / 2.0, 0);
         Self {
-            root: root,
+            root: Octant::empty(),
+            center,
+            half_size: size / 2.0,
+        }
+    }
+
+    /// Size of the voxels at `max_depth`, clamped to `MAX_GENERATE_DEPTH` like the generators.
+    pub fn leaf_size(&self, max_depth: u8) -> Vec3 {
+        self.half_size * 2.0 / (1u32 << max_depth.min(MAX_GENERATE_DEPTH)) as f32
+    }
+
+    pub fn bounds(&self) -> NodeBounds {
+        NodeBounds {
+            center: self.center,
+            half_size: self.half_size,
+            depth: 0,
+        }
+    }
+
+    /// Walks the tree depth-first, handing `f` every node with its bounds.
+    /// The children of a node are only visited if `f` returns true.
+    pub fn visit<F>(&self, mut f: F)
+    where
+        F: FnMut(&Octant, NodeBounds) -> bool
+    {
+        let mut stack = vec![(&self.root, self.bounds())];
+        while let Some((octant, bounds)) = stack.pop() {
+            if f(octant, bounds) {
+                for (i, child) in octant.children() {
+                    stack.push((child, bounds.child(i)));
+                }
+            }
+        }
+    }
+
+    pub fn leaves(&self) -> Leaves<'_> {
+        Leaves {
+            stack: vec![(&self.root, self.bounds())],
+        }
+    }
+
+    /// Iterates over every leaf that overlaps the box from `min` to `max`, skipping subtrees
+    /// outside of it. Leaves that only touch the box with a face, edge or corner are left out,
+    /// except along axes where the box is flat: `query_aabb(p, p)` returns the leaf containing
+    /// `p`, or all of them when `p` lies on a face, edge or corner between leaves.
+    pub fn query_aabb(&self, min: Vec3, max: Vec3) -> AabbLeaves<'_> {
+        let mut query = AabbLeaves {
+            stack: Vec::new(),
+            min: min.min(max),
+            max: min.max(max),
+        };
+        let bounds = self.bounds();
+        if query.overlaps(bounds) {
+            query.stack.push((&self.root, bounds));
+        }
+        query
+    }
+
+    /// Recomputes the colours and coverage of all interior nodes, see `Octant::update_lod`.
+    /// The editing functions of the tree keep them up to date, this is only needed after
+    /// changing nodes by hand.
+    pub fn update_lod(&mut self) {
+        VoxelOctree::update_octant_lod(&mut self.root);
+    }
+
+    fn update_octant_lod(octant: &mut Octant) {
+        for i in 0..8 {
+            if let Some(child) = octant.child_mut(i) {
+                VoxelOctree::update_octant_lod(child);
+            }
+        }
+        octant.update_lod();
+    }
+
+    /// Returns the leaf containing `pos`, or `None` if that space is empty.
+    pub fn get_voxel(&self, pos: Vec3) -> Option<VoxelInfo> {
+        self.get_voxel_at_depth(pos, u8::MAX)
+    }
+
+    /// Like `get_voxel`, but stops descending at `depth` and returns the node there.
+    pub fn get_voxel_at_depth(&self, pos: Vec3, depth: u8) -> Option<VoxelInfo> {
+        let (octant, bounds) = self.node_at(pos, depth)?;
+        //Only report interior nodes that actually hold something
+        if octant.is_leaf() || octant.child_mask() != 0 {
+            Some(octant.info(bounds))
+        } else {
+            None
+        }
+    }
+
+    //Deepest node containing `pos` that is a leaf or at `depth`, or None when the walk
+    //down runs into empty space first
+    pub(crate) fn node_at(&self, pos: Vec3, depth: u8) -> Option<(&Octant, NodeBounds)> {
+        let mut bounds = self.bounds();
+        if !bounds.contains(pos) {
+            return None;
+        }
+
+        let mut octant = &self.root;
+        while !octant.is_leaf() && bounds.depth < depth {
+            let i = bounds.child_index(pos);
+            octant = octant.child(i)?;
+            bounds = bounds.child(i);
+        }
+        Some((octant, bounds))
+    }
+
+    /// Returns the node containing `pos` where `lod` stops, seen from `eye`. Interior nodes
+    /// come with their averaged colour and coverage.
+    pub fn get_voxel_lod(&self, pos: Vec3, eye: Vec3, lod: Lod) -> Option<VoxelInfo> {
+        let mut bounds = self.bounds();
+        if !bounds.contains(pos) {
+            return None;
+        }
+
+        let mut octant = &self.root;
+        while !octant.is_leaf() && !lod.stops_at(bounds, bounds.distance(eye)) {
+            let i = bounds.child_index(pos);
+            octant = octant.child(i)?;
+            bounds = bounds.child(i);
+        }
+        if octant.coverage() == 0 {
+            return None;
+        }
+        Some(octant.info(bounds))
+    }
+
+    /// Writes a voxel of size `depth` at `pos`, subdividing the tree as needed.
+    /// Returns whether the tree changed.
+    pub fn set_voxel(&mut self, pos: Vec3, depth: u8, color: Color) -> bool {
+        let bounds = self.bounds();
+        if !bounds.contains(pos) {
+            return false;
+        }
+        VoxelOctree::set_octant(&mut self.root, bounds, pos, depth, color)
+    }
+
+    fn set_octant(octant: &mut Octant, bounds: NodeBounds, pos: Vec3, depth: u8, color: Color) -> bool {
+        if octant.is_leaf() && octant.color() == color {
+            //Already filled with this colour, possibly by a coarser leaf
+            return false;
+        }
+
+        if bounds.depth >= depth {
+            let (r, g, b) = color;
+            *octant = Octant::leaf(r,g,b);
+            return true;
+        }
+
+        if octant.is_leaf() {
+            octant.split();
+        }
+
+        let i = bounds.child_index(pos);
+        if !octant.has_child(i) {
+            octant.set_child(i, Some(Octant::empty()));
+        }
+        let changed = VoxelOctree::set_octant(octant.child_mut(i).unwrap(), bounds.child(i), pos, depth, color);
+        octant.update_lod();
+        changed
+    }
+
+    /// Carves out the voxel of size `depth` at `pos`, splitting coarser leaves and
+    /// pruning nodes that end up without children. Returns whether the tree changed.
+    pub fn remove_voxel(&mut self, pos: Vec3, depth: u8) -> bool {
+        let bounds = self.bounds();
+        if !bounds.contains(pos) {
+            return false;
+        }
+        if depth == 0 {
+            let changed = self.root.is_leaf() || self.root.child_mask() != 0;
+            self.root = Octant::empty();
+            return changed;
+        }
+        VoxelOctree::remove_octant(&mut self.root, bounds, pos, depth)
+    }
+
+    fn remove_octant(octant: &mut Octant, bounds: NodeBounds, pos: Vec3, depth: u8) -> bool {
+        if octant.is_leaf() {
+            octant.split();
+        }
+
+        let i = bounds.child_index(pos);
+        if !octant.has_child(i) {
+            return false;
+        }
+
+        let changed = if bounds.depth + 1 >= depth {
+            octant.set_child(i, None);
+            true
+        } else {
+            let child = octant.child_mut(i).unwrap();
+            let changed = VoxelOctree::remove_octant(child, bounds.child(i), pos, depth);
+            if !child.is_leaf() && child.child_mask() == 0 {
+                octant.set_child(i, None);
+            }
+            changed
+        };
+        octant.update_lod();
+        changed
+    }
+
+    /// Casts a ray through the tree, returning the first leaf hit within `max_dist`.
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<RayHit> {
+        self.raycast_lod(origin, dir, max_dist, Lod::Depth(u8::MAX))
+    }
+
+    /// Like `raycast`, but treats the first node where `lod` stops as solid, as seen from
+    /// `origin`. Nodes that are only partially filled are hit with their averaged colour.
+    pub fn raycast_lod(&self, origin: Vec3, dir: Vec3, max_dist: f32, lod: Lod) -> Option<RayHit> {
+        if self.root.coverage() == 0 {
+            return None;
         }
+        raycast_tree(LodOctant { octant: &self.root, lod }, self.bounds(), origin, dir, max_dist)
     }
 
-    fn gen_octant<F>(octant: &mut Octant, max_depth: u8, nodes_generated: &mut usize, contains_voxel: F)
+    fn gen_octant<F>(octant: &mut Octant, bounds: NodeBounds, max_depth: u8, nodes_generated: &mut usize, contains_voxel: F)
     where
         F: Fn(Vec3, Vec3, Vec3) -> OctantFillState + Copy
     {
-        let mut i = 0;
-        for ix in 0..2 {
-            for iy in 0..2 {
-                for iz in 0..2 {
-                    let x = (ix * 2 - 1) as f32;
-                    let y = (iy * 2 - 1) as f32;
-                    let z = (iz * 2 - 1) as f32;
-                    let sign = vec3(x,y,z);
-                    let child_pos = octant.center + octant.half_size * sign * 0.5;
-                    let child_half_size = octant.half_size / 2.0;
-                    let child_inner = child_pos + child_half_size * -sign;
-                    let child_outer = child_pos + child_half_size * sign;
-                    let vox_status = contains_voxel(child_pos, child_inner, child_outer);
-                    match vox_status {
-                        OctantFillState::Empty => {},
-                        OctantFillState::ContainsVoxel => {
-                            if octant.depth + 1 < max_depth {
-                                //We have not yet reached max depth
-                                octant.children[i] = Some(Box::new(Octant::empty(child_pos, child_half_size, octant.depth + 1)));
-                                VoxelOctree::gen_octant(octant.children[i].as_mut().unwrap(), max_depth, nodes_generated, contains_voxel);
-                            } else {
-                                //We went to the max depth, so just mark the last nodes as leaf if they are inside the sphere
-                                octant.children[i] = Some(Box::new(Octant::leaf(child_pos, child_half_size, octant.depth + 1, 255,0,255)));
-                            }
-                            i += 1;
-                            *nodes_generated += 1;
-                        },
-                        OctantFillState::Full => {
-                            //The whole octant is filled. We can just make it a leaf node immediately
-                            octant.children[i] = Some(Box::new(Octant::leaf(child_pos, child_half_size, octant.depth + 1, 255,0,255)));
-                            i += 1;
-                            *nodes_generated += 1;
-                        },
+        for i in 0..8 {
+            let sign = Octant::child_sign(i);
+            let child_bounds = bounds.child(i);
+            let child_pos = child_bounds.center;
+            let child_inner = child_pos + child_bounds.half_size * -sign;
+            let child_outer = child_pos + child_bounds.half_size * sign;
+            let vox_status = contains_voxel(child_pos, child_inner, child_outer);
+            match vox_status {
+                OctantFillState::Empty => {},
+                OctantFillState::ContainsVoxel((r, g, b)) => {
+                    if child_bounds.depth < max_depth {
+                        //We have not yet reached max depth
+                        octant.set_child(i, Some(Octant::empty()));
+                        VoxelOctree::gen_octant(octant.child_mut(i).unwrap(), child_bounds, max_depth, nodes_generated, contains_voxel);
+                    } else {
+                        //We went to the max depth, so just mark the last nodes as leaf if they are inside the sphere
+                        octant.set_child(i, Some(Octant::leaf(r,g,b)));
                     }
-                }
+                    *nodes_generated += 1;
+                },
+                OctantFillState::Full((r, g, b)) => {
+                    //The whole octant is filled. We can just make it a leaf node immediately
+                    octant.set_child(i, Some(Octant::leaf(r,g,b)));
+                    *nodes_generated += 1;
+                },
             }
         }
+        octant.update_lod();
     }
 
     pub fn generate<F>(&mut self, max_depth: u8, contains_voxel: F) -> usize
@@ -129,21 +740,24 @@ impl VoxelOctree {
         F: Fn(Vec3, Vec3, Vec3) -> OctantFillState + Copy
     {
         let mut nodes_generated = 0;
-        VoxelOctree::gen_octant(&mut self.root, max_depth, &mut nodes_generated, contains_voxel);
+        let bounds = self.bounds();
+        let max_depth = max_depth.min(MAX_GENERATE_DEPTH);
+        VoxelOctree::gen_octant(&mut self.root, bounds, max_depth, &mut nodes_generated, contains_voxel);
         nodes_generated
     }
 
     pub fn generate_sphere(&mut self, radius: f32, max_depth: u8) {
         let mut nodes_generated = 0;
+        let bounds = self.bounds();
 
-        VoxelOctree::gen_octant(&mut self.root, max_depth, &mut nodes_generated, |center, inner, outer| {
+        VoxelOctree::gen_octant(&mut self.root, bounds, max_depth, &mut nodes_generated, |_center, inner, outer| {
             let mut status = OctantFillState::Empty;
             let min = inner.abs().min(outer.abs());
             let max = inner.abs().max(outer.abs());
             if min.length() < radius {
-                status = OctantFillState::ContainsVoxel;
+                status = OctantFillState::ContainsVoxel(DEFAULT_COLOR);
                 if max.length() < radius {
-                    status = OctantFillState::Full;
+                    status = OctantFillState::Full(DEFAULT_COLOR);
                 }
             }
             status
@@ -152,139 +766,456 @@ impl VoxelOctree {
         trace!("Nodes generated: {}", nodes_generated);
     }
 
-    fn add_octant_cube(octant: &Octant, indices: &mut Vec<u32>, positions: &mut Vec<f64>) {
-        let v1 = octant.center + octant.half_size * vec3(-1.0,-1.0,-1.0);
-        let v2 = octant.center + octant.half_size * vec3( 1.0,-1.0,-1.0);
-        let v3 = octant.center + octant.half_size * vec3( 1.0, 1.0,-1.0);
-        let v4 = octant.center + octant.half_size * vec3(-1.0, 1.0,-1.0);
-
-        let v5 = octant.center + octant.half_size * vec3(-1.0,-1.0, 1.0);
-        let v6 = octant.center + octant.half_size * vec3( 1.0,-1.0, 1.0);
-        let v7 = octant.center + octant.half_size * vec3( 1.0, 1.0, 1.0);
-        let v8 = octant.center + octant.half_size * vec3(-1.0, 1.0, 1.0);
-
-        let cube_idx_start = positions.len() / 3;
-        let v1_idx = cube_idx_start;
-        let v2_idx = cube_idx_start + 1;
-        let v3_idx = cube_idx_start + 2;
-        let v4_idx = cube_idx_start + 3;
-        let v5_idx = cube_idx_start + 4;
-        let v6_idx = cube_idx_start + 5;
-        let v7_idx = cube_idx_start + 6;
-        let v8_idx = cube_idx_start + 7;
-
-        positions.push(v1.x as f64);
-        positions.push(v1.y as f64);
-        positions.push(v1.z as f64);
-
-        positions.push(v2.x as f64);
-        positions.push(v2.y as f64);
-        positions.push(v2.z as f64);
-
-        positions.push(v3.x as f64);
-        positions.push(v3.y as f64);
-        positions.push(v3.z as f64);
-
-        positions.push(v4.x as f64);
-        positions.push(v4.y as f64);
-        positions.push(v4.z as f64);
-
-        positions.push(v5.x as f64);
-        positions.push(v5.y as f64);
-        positions.push(v5.z as f64);
-
-        positions.push(v6.x as f64);
-        positions.push(v6.y as f64);
-        positions.push(v6.z as f64);
-
-        positions.push(v7.x as f64);
-        positions.push(v7.y as f64);
-        positions.push(v7.z as f64);
-
-        positions.push(v8.x as f64);
-        positions.push(v8.y as f64);
-        positions.push(v8.z as f64);
-
-        //Back
-        indices.push(v1_idx as u32);
-        indices.push(v2_idx as u32);
-        indices.push(v3_idx as u32);
-        //
-        indices.push(v1_idx as u32);
-        indices.push(v3_idx as u32);
-        indices.push(v4_idx as u32);
-
-        //Front
-        indices.push(v5_idx as u32);
-        indices.push(v8_idx as u32);
-        indices.push(v7_idx as u32);
-        //
-        indices.push(v7_idx as u32);
-        indices.push(v6_idx as u32);
-        indices.push(v5_idx as u32);
-
-        //Right
-        indices.push(v2_idx as u32);
-        indices.push(v6_idx as u32);
-        indices.push(v7_idx as u32);
-        //
-        indices.push(v7_idx as u32);
-        indices.push(v3_idx as u32);
-        indices.push(v2_idx as u32);
-
-        //Left
-        indices.push(v4_idx as u32);
-        indices.push(v8_idx as u32);
-        indices.push(v5_idx as u32);
-        //
-        indices.push(v5_idx as u32);
-        indices.push(v1_idx as u32);
-        indices.push(v4_idx as u32);
-
-        //Top
-        indices.push(v4_idx as u32);
-        indices.push(v3_idx as u32);
-        indices.push(v7_idx as u32);
-        //
-        indices.push(v7_idx as u32);
-        indices.push(v8_idx as u32);
-        indices.push(v4_idx as u32);
-
-        //Bottom
-        indices.push(v5_idx as u32);
-        indices.push(v6_idx as u32);
-        indices.push(v2_idx as u32);
-        //
-        indices.push(v2_idx as u32);
-        indices.push(v1_idx as u32);
-        indices.push(v5_idx as u32);
-    }
-
-    fn export_octant_walk(octant: &Octant, indices: &mut Vec<u32>, positions: &mut Vec<f64>) {
+    fn carve_octant<F>(octant: &mut Octant, bounds: NodeBounds, max_depth: u8, nodes_removed: &mut usize, carve: F)
+    where
+        F: Fn(Vec3, Vec3, Vec3) -> OctantFillState + Copy
+    {
+        for i in 0..8 {
+            if !octant.is_leaf() && !octant.has_child(i) {
+                continue;
+            }
+            let sign = Octant::child_sign(i);
+            let child_bounds = bounds.child(i);
+            let child_pos = child_bounds.center;
+            let child_inner = child_pos + child_bounds.half_size * -sign;
+            let child_outer = child_pos + child_bounds.half_size * sign;
+            match carve(child_pos, child_inner, child_outer) {
+                OctantFillState::Empty => {},
+                OctantFillState::ContainsVoxel(_) if child_bounds.depth < max_depth => {
+                    if octant.is_leaf() {
+                        octant.split();
+                    }
+                    let child = octant.child_mut(i).unwrap();
+                    VoxelOctree::carve_octant(child, child_bounds, max_depth, nodes_removed, carve);
+                    if !child.is_leaf() && child.child_mask() == 0 {
+                        octant.set_child(i, None);
+                        *nodes_removed += 1;
+                    }
+                },
+                _ => {
+                    //Fully carved, or as deep as it goes
+                    if octant.is_leaf() {
+                        octant.split();
+                    }
+                    octant.set_child(i, None);
+                    *nodes_removed += 1;
+                },
+            }
+        }
+        octant.update_lod();
+    }
+
+    /// The opposite of `generate`: removes everything the classifier marks as filled, with
+    /// the same rules for subdividing. The colours it returns are ignored. Returns the number
+    /// of nodes removed.
+    pub fn carve<F>(&mut self, max_depth: u8, carve: F) -> usize
+    where
+        F: Fn(Vec3, Vec3, Vec3) -> OctantFillState + Copy
+    {
+        let mut nodes_removed = 0;
+        let bounds = self.bounds();
+        if !self.root.is_leaf() && self.root.child_mask() == 0 {
+            return 0;
+        }
+        let max_depth = max_depth.min(MAX_GENERATE_DEPTH);
+        VoxelOctree::carve_octant(&mut self.root, bounds, max_depth, &mut nodes_removed, carve);
+        nodes_removed
+    }
+
+    //True if the side of the octant facing `side` along `axis` is completely solid
+    pub(crate) fn side_covered(octant: &Octant, axis: usize, side: f32) -> bool {
         if octant.is_leaf() {
-            VoxelOctree::add_octant_cube(octant, indices, positions);
+            return true;
         }
+        (0..8).filter(|&i| Octant::child_sign(i)[axis] == side).all(|i| match octant.child(i) {
+            Some(child) => VoxelOctree::side_covered(child, axis, side),
+            None => false,
+        })
+    }
+}
 
-        for i in 0..8 {
-            match &octant.children[i] {
-                Some(child) => VoxelOctree::export_octant_walk(&child, indices, positions),
-                None => {},
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    //Simple xorshift generator, returning numbers below `n`
+    pub(crate) fn rng(seed: u64) -> impl FnMut(u64) -> u64 {
+        let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        move |n| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % n
+        }
+    }
+
+    //Tree of 16 units around the origin with leaves of mixed depths, down to depth 4
+    pub(crate) fn random_tree(seed: u64) -> VoxelOctree {
+        let mut next = rng(seed);
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        for _ in 0..40 {
+            let pos = vec3(next(160) as f32, next(160) as f32, next(160) as f32) / 10.0 - Vec3::splat(8.0);
+            let depth = 1 + next(4) as u8;
+            if next(4) == 0 {
+                tree.remove_voxel(pos, depth);
+            } else {
+                tree.set_voxel(pos, depth, [(200, 0, 0), (0, 200, 0)][next(2) as usize]);
+            }
+        }
+        tree
+    }
+
+    //Plain grid of the unit voxels of a `random_tree`, to compare edits against
+    pub(crate) struct Model {
+        cells: Vec<Option<Color>>,
+    }
+
+    impl Model {
+        fn new() -> Self {
+            Self { cells: vec![None; 16 * 16 * 16] }
+        }
+
+        pub(crate) fn center(i: usize) -> Vec3 {
+            vec3((i / 256) as f32, (i / 16 % 16) as f32, (i % 16) as f32) - Vec3::splat(7.5)
+        }
+
+        //Cells of the node of `depth` containing `pos`
+        fn block(pos: Vec3, depth: u8) -> impl Iterator<Item = usize> {
+            let size = 16 >> depth.min(4);
+            let lo = ((pos + Vec3::splat(8.0)) / size as f32).floor();
+            let lo = [lo.x, lo.y, lo.z].map(|v| (v as usize).min(15 / size) * size);
+            (0..size * size * size).map(move |i| (lo[0] + i / (size * size)) * 256 + (lo[1] + i / size % size) * 16 + lo[2] + i % size)
+        }
+
+        fn set(&mut self, pos: Vec3, depth: u8, color: Option<Color>) {
+            for i in Model::block(pos, depth) {
+                self.cells[i] = color;
+            }
+        }
+
+        fn assert_matches(&self, tree: &VoxelOctree) {
+            for (i, &color) in self.cells.iter().enumerate() {
+                let pos = Model::center(i);
+                assert_eq!(tree.get_voxel(pos).map(|info| info.color), color, "voxel at {}", pos);
+            }
+        }
+    }
+
+    //Interior nodes below the root always have children, and only allocate them when they do
+    fn assert_pruned(octant: &Octant, root: bool) {
+        if octant.is_leaf() {
+            assert!(octant.children.is_none(), "leaf with children");
+            return;
+        }
+        assert!(root || octant.child_mask() != 0, "empty interior node");
+        assert_eq!(octant.children.is_some(), octant.child_mask() != 0);
+        for (_, child) in octant.children() {
+            assert_pruned(child, false);
+        }
+    }
+
+    #[test]
+    fn set_voxel_splits_leaves() {
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        assert!(tree.set_voxel(vec3(-4.0, -4.0, -4.0), 1, (1, 1, 1)));
+        //Already that colour
+        assert!(!tree.set_voxel(vec3(-5.0, -5.0, -5.0), 3, (1, 1, 1)));
+        assert!(!tree.set_voxel(vec3(9.0, 0.0, 0.0), 3, (2, 2, 2)));
+
+        assert!(tree.set_voxel(vec3(-1.5, -1.5, -1.5), 3, (2, 2, 2)));
+        let info = tree.get_voxel(vec3(-1.5, -1.5, -1.5)).unwrap();
+        assert_eq!((info.color, info.depth, info.center, info.half_size), ((2, 2, 2), 3, Vec3::splat(-1.0), Vec3::ONE));
+        //The rest of the old leaf is split into leaves as big as possible
+        let info = tree.get_voxel(vec3(-3.5, -1.5, -1.5)).unwrap();
+        assert_eq!((info.color, info.depth), ((1, 1, 1), 3));
+        let info = tree.get_voxel(vec3(-6.0, -6.0, -6.0)).unwrap();
+        assert_eq!((info.color, info.depth), ((1, 1, 1), 2));
+        assert_eq!(tree.get_voxel(vec3(4.0, 4.0, 4.0)), None);
+        assert_pruned(&tree.root, true);
+    }
+
+    #[test]
+    fn set_voxel_matches_grid() {
+        for seed in 0..10 {
+            let mut next = rng(seed);
+            let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+            let mut model = Model::new();
+            for _ in 0..30 {
+                let pos = vec3(next(160) as f32, next(160) as f32, next(160) as f32) / 10.0 - Vec3::splat(8.0);
+                let depth = next(5) as u8;
+                let color = ((next(3) * 100) as u8, 0, 0);
+                tree.set_voxel(pos, depth, color);
+                model.set(pos, depth, Some(color));
+            }
+            model.assert_matches(&tree);
+            assert_pruned(&tree.root, true);
+        }
+    }
+
+    #[test]
+    fn remove_voxel_splits_and_prunes() {
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        tree.set_voxel(vec3(-4.0, -4.0, -4.0), 1, (1, 1, 1));
+        assert!(tree.remove_voxel(vec3(-1.5, -1.5, -1.5), 3));
+        assert_eq!(tree.get_voxel(vec3(-1.5, -1.5, -1.5)), None);
+        //The node around the hole keeps the other 7 voxels
+        let (parent, _) = tree.node_at(vec3(-1.5, -1.5, -1.5), 2).unwrap();
+        assert_eq!(parent.child_mask().count_ones(), 7);
+        assert!(parent.children().all(|(_, child)| child.is_leaf() && child.color() == (1, 1, 1)));
+        assert!(!tree.remove_voxel(vec3(-1.5, -1.5, -1.5), 3));
+        assert!(!tree.remove_voxel(vec3(4.0, 4.0, 4.0), 2));
+        assert!(!tree.remove_voxel(vec3(0.0, 9.0, 0.0), 2));
+
+        //Removing the last voxel of a branch prunes it all the way up
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        tree.set_voxel(vec3(0.5, 0.5, 0.5), 4, (1, 1, 1));
+        assert!(tree.remove_voxel(vec3(0.5, 0.5, 0.5), 4));
+        assert!(tree.root.children.is_none() && tree.root.child_mask() == 0);
+
+        //Depth 0 clears the whole tree
+        tree.set_voxel(vec3(0.5, 0.5, 0.5), 4, (1, 1, 1));
+        assert!(tree.remove_voxel(vec3(-7.0, 3.0, 2.0), 0));
+        assert!(tree.root.children.is_none() && !tree.root.is_leaf());
+        assert!(!tree.remove_voxel(vec3(-7.0, 3.0, 2.0), 0));
+    }
+
+    #[test]
+    fn edits_match_grid() {
+        for seed in 0..10 {
+            let mut next = rng(seed);
+            let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+            let mut model = Model::new();
+            for _ in 0..60 {
+                let pos = vec3(next(160) as f32, next(160) as f32, next(160) as f32) / 10.0 - Vec3::splat(8.0);
+                let depth = 1 + next(4) as u8;
+                if next(3) == 0 {
+                    tree.remove_voxel(pos, depth);
+                    model.set(pos, depth, None);
+                } else {
+                    let color = ((next(3) * 100) as u8, 0, 0);
+                    tree.set_voxel(pos, depth, color);
+                    model.set(pos, depth, Some(color));
+                }
+            }
+            model.assert_matches(&tree);
+            assert_pruned(&tree.root, true);
+        }
+    }
+
+    //Closest of `nodes` along the ray, tested one by one, with the distance to the next one
+    //so ties can be told apart
+    fn first_hit(nodes: &[(Color, NodeBounds)], origin: Vec3, dir: Vec3, max_dist: f32) -> Option<(f32, Color, f32)> {
+        let dir = dir.normalize();
+        let mut hits: Vec<(f32, Color)> = nodes.iter().filter_map(|&(color, bounds)| {
+            let (mut near, mut far) = (0.0f32, f32::INFINITY);
+            for i in 0..3 {
+                let (min, max) = (bounds.min()[i], bounds.max()[i]);
+                if dir[i] == 0.0 {
+                    if origin[i] < min || origin[i] > max {
+                        return None;
+                    }
+                    continue;
+                }
+                let (t0, t1) = ((min - origin[i]) / dir[i], (max - origin[i]) / dir[i]);
+                near = near.max(t0.min(t1));
+                far = far.min(t0.max(t1));
+            }
+            if near <= far && near <= max_dist { Some((near, color)) } else { None }
+        }).collect();
+        hits.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        let next = hits.get(1).map_or(f32::INFINITY, |hit| hit.0);
+        hits.first().map(|&(distance, color)| (distance, color, next))
+    }
+
+    //Random rays from in and around the tree, compared against testing every node
+    pub(crate) fn check_rays<F>(seed: u64, nodes: &[(Color, NodeBounds)], mut cast: F)
+    where
+        F: FnMut(Vec3, Vec3, f32) -> Option<RayHit>
+    {
+        let mut next = rng(seed);
+        let mut random = move |scale: f32| (next(2001) as f32 / 1000.0 - 1.0) * scale;
+        for _ in 0..300 {
+            //Kept off the node boundaries, where it would be in two nodes at once
+            let origin = vec3(random(12.0), random(12.0), random(12.0)) + vec3(0.0013, 0.0029, 0.0041);
+            let dir = vec3(random(1.0), random(1.0), random(1.0));
+            if dir == Vec3::ZERO {
+                continue;
             }
+            let max_dist = 25.0 + random(20.0);
+            match (cast(origin, dir, max_dist), first_hit(nodes, origin, dir, max_dist)) {
+                (None, None) => {},
+                (Some(hit), Some((distance, color, next))) => {
+                    assert!((hit.distance - distance).abs() < 1e-4, "distance from {} along {}", origin, dir);
+                    assert!((hit.position - (origin + dir.normalize() * distance)).length() < 1e-3);
+                    if next - distance > 1e-4 {
+                        assert_eq!(hit.color, color, "colour from {} along {}", origin, dir);
+                    }
+                },
+                (hit, expected) => panic!("ray from {} along {}: {:?} instead of {:?}", origin, dir, hit, expected),
+            }
+        }
+    }
+
+    #[test]
+    fn raycast_matches_leaves() {
+        for seed in 0..10 {
+            let tree = random_tree(seed);
+            let leaves: Vec<(Color, NodeBounds)> = tree.leaves().map(|(octant, bounds)| (octant.color(), bounds)).collect();
+            check_rays(seed, &leaves, |origin, dir, max_dist| tree.raycast(origin, dir, max_dist));
         }
     }
 
-    pub fn export_mesh(&self) {
-        use tri_mesh::prelude::*;
+    #[test]
+    fn raycast_edge_cases() {
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        assert_eq!(tree.raycast(Vec3::splat(-20.0), Vec3::ONE, 100.0), None);
+        tree.set_voxel(vec3(2.5, 0.5, 3.5), 4, (1, 2, 3));
+
+        //Parallel to two of the axes, through the middle of the voxel and right past it
+        let hit = tree.raycast(vec3(2.5, 0.5, -20.0), vec3(0.0, 0.0, 5.0), 100.0).unwrap();
+        assert_eq!((hit.distance, hit.position, hit.normal, hit.color, hit.depth), (23.0, vec3(2.5, 0.5, 3.0), vec3(0.0, 0.0, -1.0), (1, 2, 3), 4));
+        assert_eq!(tree.raycast(vec3(1.5, 0.5, -20.0), Vec3::Z, 100.0), None);
+        assert_eq!(tree.raycast(vec3(2.5, 0.5, 20.0), -Vec3::X, 100.0), None);
+        let hit = tree.raycast(vec3(20.0, 0.5, 3.5), -Vec3::X, 100.0).unwrap();
+        assert_eq!((hit.distance, hit.normal), (17.0, Vec3::X));
+
+        //Out of reach
+        assert_eq!(tree.raycast(vec3(2.5, 0.5, -20.0), Vec3::Z, 22.9), None);
+        assert!(tree.raycast(vec3(2.5, 0.5, -20.0), Vec3::Z, 23.0).is_some());
+
+        //Starting inside the voxel hits it right away
+        let origin = vec3(2.7, 0.2, 3.9);
+        let hit = tree.raycast(origin, vec3(-1.0, 0.3, 0.2), 100.0).unwrap();
+        assert_eq!((hit.distance, hit.position, hit.color), (0.0, origin, (1, 2, 3)));
+
+        assert_eq!(tree.raycast(origin, Vec3::ZERO, 100.0), None);
+    }
+
+    //Interior nodes hold the colour and coverage `update_lod` computes from their children
+    fn assert_lod(octant: &Octant) {
+        for (_, child) in octant.children() {
+            assert_lod(child);
+        }
+        let mut updated = octant.clone();
+        updated.update_lod();
+        assert_eq!(updated.data, octant.data);
+    }
 
-        let mut indices: Vec<u32> = Vec::new();
-        let mut positions: Vec<f64> = Vec::new();
+    #[test]
+    fn lod_averages_children() {
+        let mut octant = Octant::empty();
+        octant.set_child(0, Some(Octant::leaf(255, 0, 0)));
+        octant.set_child(5, Some(Octant::leaf(0, 0, 255)));
+        octant.update_lod();
+        assert_eq!((octant.color(), octant.coverage()), ((128, 0, 128), 64));
 
-        VoxelOctree::export_octant_walk(&self.root, &mut indices, &mut positions);
+        //Weighted by coverage, rounded up so a single voxel still shows up
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        tree.set_voxel(vec3(0.5, 0.5, 0.5), 4, (200, 100, 0));
+        tree.set_voxel(vec3(-4.0, -4.0, -4.0), 1, (0, 100, 200));
+        let coverage = |depth| tree.node_at(vec3(0.5, 0.5, 0.5), depth).unwrap().0.coverage();
+        assert_eq!([coverage(1), coverage(2), coverage(3), coverage(4)], [1, 4, 32, 255]);
+        assert_eq!(tree.root.coverage(), 32);
+        let (r, g, b) = tree.root.color();
+        assert_eq!((r, g, b), (1, 100, 199));
 
-        trace!("Indices: {}", indices.len());
-        trace!("Positions: {}", positions.len());
+        tree.remove_voxel(vec3(-4.0, -4.0, -4.0), 1);
+        assert_eq!((tree.root.color(), tree.root.coverage()), ((200, 100, 0), 1));
+        tree.remove_voxel(vec3(0.5, 0.5, 0.5), 4);
+        assert_eq!(tree.root.coverage(), 0);
+    }
+
+    #[test]
+    fn edits_keep_lod() {
+        for seed in 0..10 {
+            let tree = random_tree(seed);
+            assert_lod(&tree.root);
+        }
+    }
+
+    #[test]
+    fn get_voxel_lod_stops_early() {
+        let tree = random_tree(4);
+        let far = Vec3::splat(1e6);
+        for i in 0..16 * 16 * 16 {
+            let pos = Model::center(i);
+            for depth in 0..5 {
+                let expected = tree.get_voxel_at_depth(pos, depth).filter(|info| info.coverage > 0.0);
+                assert_eq!(tree.get_voxel_lod(pos, far, Lod::Depth(depth)), expected);
+            }
+            //Seen from right next to it nothing is small enough to stop at
+            assert_eq!(tree.get_voxel_lod(pos, pos, Lod::ProjectedSize(0.1)), tree.get_voxel(pos));
+            //And from far away the whole tree is a single node
+            let root = tree.get_voxel_lod(pos, far, Lod::ProjectedSize(0.1)).unwrap();
+            assert_eq!((root.depth, root.coverage), (0, tree.root.coverage() as f32 / 255.0));
+        }
+    }
+
+    #[test]
+    fn raycast_lod_matches_nodes() {
+        for seed in 0..10 {
+            let tree = random_tree(seed);
+            for depth in 1..3 {
+                //Leaves above the LOD depth, and the nodes at that depth that hold anything
+                let mut nodes = Vec::new();
+                tree.visit(|octant, bounds| {
+                    if octant.is_leaf() || (bounds.depth == depth && octant.coverage() > 0) {
+                        nodes.push((octant.color(), bounds));
+                    }
+                    !octant.is_leaf() && bounds.depth < depth
+                });
+                check_rays(seed, &nodes, |origin, dir, max_dist| {
+                    let hit = tree.raycast_lod(origin, dir, max_dist, Lod::Depth(depth))?;
+                    let (octant, _) = tree.node_at(hit.position + dir.normalize() * 1e-3, hit.depth).unwrap();
+                    assert_eq!(hit.coverage, octant.coverage() as f32 / 255.0);
+                    Some(hit)
+                });
+            }
+        }
+    }
+
+    fn leaf_keys<'a>(leaves: impl Iterator<Item = (&'a Octant, NodeBounds)>) -> Vec<(u8, [u32; 3])> {
+        let mut keys: Vec<(u8, [u32; 3])> = leaves.map(|(_, b)| (b.depth, [b.center.x, b.center.y, b.center.z].map(f32::to_bits))).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn query_aabb_matches_leaves() {
+        for seed in 0..10 {
+            let tree = random_tree(seed);
+            let mut next = rng(seed + 100);
+            for _ in 0..50 {
+                //Corners on the voxel grid, so plenty of leaves only touch the box
+                let mut corner = || vec3(next(21) as f32, next(21) as f32, next(21) as f32) - Vec3::splat(10.0);
+                let (a, b) = (corner(), corner());
+                let (min, max) = (a.min(b), a.max(b));
+                let expected = tree.leaves().filter(|(_, bounds)| (0..3).all(|i| {
+                    let (lo, hi) = (bounds.min()[i], bounds.max()[i]);
+                    if min[i] == max[i] { lo <= min[i] && min[i] <= hi } else { lo < max[i] && min[i] < hi }
+                }));
+                assert_eq!(leaf_keys(tree.query_aabb(b, a)), leaf_keys(expected), "box from {} to {}", min, max);
+            }
+        }
+    }
 
-        let mesh = MeshBuilder::new().with_indices(indices).with_positions(positions).build().unwrap();
-        std::fs::write("octree.obj", mesh.parse_as_obj()).unwrap();
+    #[test]
+    fn query_aabb_points() {
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        tree.set_voxel(vec3(0.5, 0.5, 0.5), 4, (1, 1, 1));
+        tree.set_voxel(vec3(1.5, 0.5, 0.5), 4, (2, 2, 2));
+        let colors = |min: Vec3, max: Vec3| {
+            let mut colors: Vec<Color> = tree.query_aabb(min, max).map(|(octant, _)| octant.color()).collect();
+            colors.sort_unstable();
+            colors
+        };
+        let p = vec3(0.3, 0.6, 0.9);
+        assert_eq!(colors(p, p), vec![(1, 1, 1)]);
+        //On the face between the two voxels, and a flat box over it
+        assert_eq!(colors(vec3(1.0, 0.5, 0.5), vec3(1.0, 0.5, 0.5)), vec![(1, 1, 1), (2, 2, 2)]);
+        assert_eq!(colors(vec3(1.0, 0.0, 0.0), vec3(1.0, 1.0, 1.0)), vec![(1, 1, 1), (2, 2, 2)]);
+        //A real box that only touches them
+        assert_eq!(colors(vec3(2.0, 0.0, 0.0), vec3(3.0, 1.0, 1.0)), vec![]);
+        assert_eq!(colors(vec3(5.0, 5.0, 5.0), vec3(5.0, 5.0, 5.0)), vec![]);
     }
 }
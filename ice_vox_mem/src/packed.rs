@@ -0,0 +1,270 @@
+use glam::*;
+
+use crate::octree::{raycast_tree, Color, NodeBounds, Octant, RayHit, RayNode, VoxelInfo, VoxelOctree};
+
+// Flat octree layout meant for uploading to a storage buffer.
+// Every node takes up 2 words:
+// word 0:
+//   [  0-7] u8 child mask, bit `i` set when the child at octant index `i` exists
+//   [ 8-15] u8 r
+//   [16-23] u8 g
+//   [24-31] u8 b
+//   Interior nodes hold the averaged colour of their children, for drawing at a coarser
+//   depth.
+// word 1:
+//   [ 0-31] u32 node index of the first child
+// The children of a node are stored next to each other in octant index order, skipping
+// the ones that don't exist. A node without children is a leaf. Subtrees without any
+// leaves are left out, and an empty tree has no nodes at all.
+pub const NODE_WORDS: usize = 2;
+
+pub struct PackedOctree {
+    pub nodes: Vec<u32>,
+    pub center: Vec3,
+    pub half_size: Vec3,
+}
+
+//Collects the child masks of the nodes that get packed, in the order `pack_octant` visits
+//them, leaving out children without a single leaf in their subtree. Returns whether
+//`octant` has any leaves.
+fn occupied_masks(octant: &Octant, masks: &mut Vec<u8>) -> bool {
+    let slot = masks.len();
+    masks.push(0);
+    let mut mask = 0u8;
+    for (i, child) in octant.children() {
+        let len = masks.len();
+        if occupied_masks(child, masks) {
+            mask |= 1 << i;
+        } else {
+            masks.truncate(len);
+        }
+    }
+    masks[slot] = mask;
+    octant.is_leaf() || mask != 0
+}
+
+fn pack_color(mask: u8, (r, g, b): Color) -> u32 {
+    mask as u32 | (r as u32) << 8 | (g as u32) << 16 | (b as u32) << 24
+}
+
+impl PackedOctree {
+    pub fn from_octree(tree: &VoxelOctree) -> Self {
+        let mut packed = Self {
+            nodes: Vec::new(),
+            center: tree.center,
+            half_size: tree.half_size,
+        };
+        let mut masks = Vec::new();
+        if occupied_masks(&tree.root, &mut masks) {
+            packed.nodes.resize(NODE_WORDS, 0);
+            packed.pack_octant(&tree.root, 0, &mut masks.into_iter());
+        }
+        packed
+    }
+
+    fn pack_octant(&mut self, octant: &Octant, index: usize, masks: &mut std::vec::IntoIter<u8>) {
+        let mask = masks.next().unwrap();
+
+        //Reserve a block for the children first, so they end up next to each other
+        let first_child = self.node_count();
+        self.nodes[index * NODE_WORDS] = pack_color(mask, octant.color());
+        self.nodes[index * NODE_WORDS + 1] = if mask != 0 { first_child as u32 } else { 0 };
+        self.nodes.resize((first_child + mask.count_ones() as usize) * NODE_WORDS, 0);
+
+        let mut slot = first_child;
+        for i in 0..8 {
+            if mask & (1 << i) != 0 {
+                self.pack_octant(octant.child(i).unwrap(), slot, masks);
+                slot += 1;
+            }
+        }
+    }
+
+    pub fn to_octree(&self) -> VoxelOctree {
+        let mut tree = VoxelOctree::empty(self.center, self.half_size * 2.0);
+        if !self.nodes.is_empty() {
+            self.unpack_octant(0, &mut tree.root);
+        }
+        tree
+    }
+
+    fn unpack_octant(&self, index: usize, octant: &mut Octant) {
+        let mask = self.child_mask(index);
+        if mask == 0 {
+            let (r, g, b) = self.color(index);
+            *octant = Octant::leaf(r,g,b);
+            return;
+        }
+
+        for i in 0..8 {
+            if let Some(child_index) = self.child(index, i) {
+                let mut child = Octant::empty();
+                self.unpack_octant(child_index, &mut child);
+                octant.set_child(i, Some(child));
+            }
+        }
+        octant.update_lod();
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len() / NODE_WORDS
+    }
+
+    pub fn child_mask(&self, index: usize) -> u8 {
+        (self.nodes[index * NODE_WORDS] & 0xFF) as u8
+    }
+
+    pub fn color(&self, index: usize) -> Color {
+        let word = self.nodes[index * NODE_WORDS];
+        ((word >> 8) as u8, (word >> 16) as u8, (word >> 24) as u8)
+    }
+
+    /// Node index of the child at octant index `i`, if it exists.
+    pub fn child(&self, index: usize, i: usize) -> Option<usize> {
+        let mask = self.child_mask(index);
+        if mask & (1 << i) == 0 {
+            return None;
+        }
+        //Children are packed, so skip past the siblings that come before this one
+        let offset = (mask & ((1u8 << i) - 1)).count_ones() as usize;
+        Some(self.nodes[index * NODE_WORDS + 1] as usize + offset)
+    }
+
+    pub fn bounds(&self) -> NodeBounds {
+        NodeBounds {
+            center: self.center,
+            half_size: self.half_size,
+            depth: 0,
+        }
+    }
+
+    /// Same as `VoxelOctree::get_voxel`, but walking the packed nodes.
+    pub fn get_voxel(&self, pos: Vec3) -> Option<VoxelInfo> {
+        let mut bounds = self.bounds();
+        if self.nodes.is_empty() || !bounds.contains(pos) {
+            return None;
+        }
+
+        let mut index = 0;
+        while self.child_mask(index) != 0 {
+            let i = bounds.child_index(pos);
+            index = self.child(index, i)?;
+            bounds = bounds.child(i);
+        }
+
+        Some(VoxelInfo {
+            color: self.color(index),
+            depth: bounds.depth,
+            center: bounds.center,
+            half_size: bounds.half_size,
+            is_leaf: true,
+            coverage: 1.0,
+        })
+    }
+
+    /// Same as `VoxelOctree::raycast`, but walking the packed nodes.
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<RayHit> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        raycast_tree(PackedNode { tree: self, index: 0 }, self.bounds(), origin, dir, max_dist)
+    }
+}
+
+struct PackedNode<'a> {
+    tree: &'a PackedOctree,
+    index: usize,
+}
+
+impl<'a> RayNode for PackedNode<'a> {
+    fn hit(&self, _bounds: NodeBounds, _distance: f32) -> Option<(Color, f32)> {
+        if self.tree.child_mask(self.index) == 0 {
+            Some((self.tree.color(self.index), 1.0))
+        } else {
+            None
+        }
+    }
+
+    fn child(&self, i: usize) -> Option<Self> {
+        let index = self.tree.child(self.index, i)?;
+        Some(PackedNode { tree: self.tree, index })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::tests::{check_rays, random_tree, Model};
+
+    fn assert_same(a: &Octant, b: &Octant) {
+        assert_eq!((a.data, a.children.is_some()), (b.data, b.children.is_some()));
+        for i in 0..8 {
+            match (a.child(i), b.child(i)) {
+                (Some(a), Some(b)) => assert_same(a, b),
+                (a, b) => assert_eq!(a.is_some(), b.is_some()),
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for seed in 0..10 {
+            let tree = random_tree(seed);
+            let packed = PackedOctree::from_octree(&tree);
+            assert_eq!(packed.node_count(), {
+                let mut count = 0;
+                tree.visit(|_, _| { count += 1; true });
+                count
+            });
+            let unpacked = packed.to_octree();
+            assert_eq!((unpacked.center, unpacked.half_size), (tree.center, tree.half_size));
+            assert_same(&unpacked.root, &tree.root);
+        }
+
+        let empty = PackedOctree::from_octree(&VoxelOctree::empty(Vec3::ZERO, Vec3::ONE));
+        assert_eq!(empty.node_count(), 0);
+        assert_eq!(empty.get_voxel(Vec3::ZERO), None);
+        assert!(empty.to_octree().root.children.is_none());
+    }
+
+    #[test]
+    fn leaves_out_empty_subtrees() {
+        //Built by hand, with a branch that ends without any leaves
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        let mut branch = Octant::empty();
+        let mut twig = Octant::empty();
+        twig.set_child(3, Some(Octant::empty()));
+        branch.set_child(6, Some(twig));
+        tree.root.set_child(0, Some(branch));
+        tree.root.set_child(5, Some(Octant::leaf(1, 2, 3)));
+        tree.update_lod();
+
+        let packed = PackedOctree::from_octree(&tree);
+        assert_eq!(packed.node_count(), 2);
+        assert_eq!((packed.child_mask(0), packed.child(0, 5)), (1 << 5, Some(1)));
+        let unpacked = packed.to_octree();
+        assert!(unpacked.root.child(0).is_none());
+        assert_eq!(unpacked.root.child(5).map(|leaf| leaf.color()), Some((1, 2, 3)));
+
+        tree.root.set_child(5, None);
+        assert_eq!(PackedOctree::from_octree(&tree).node_count(), 0);
+    }
+
+    #[test]
+    fn queries_match_octree() {
+        for seed in 0..10 {
+            let tree = random_tree(seed);
+            let packed = PackedOctree::from_octree(&tree);
+            for i in 0..16 * 16 * 16 {
+                let pos = Model::center(i);
+                assert_eq!(packed.get_voxel(pos), tree.get_voxel(pos), "voxel at {}", pos);
+            }
+            let leaves: Vec<(Color, NodeBounds)> = tree.leaves().map(|(octant, bounds)| (octant.color(), bounds)).collect();
+            check_rays(seed, &leaves, |origin, dir, max_dist| {
+                let hit = packed.raycast(origin, dir, max_dist);
+                assert_eq!(hit, tree.raycast(origin, dir, max_dist));
+                hit
+            });
+        }
+    }
+}
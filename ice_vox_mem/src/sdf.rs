@@ -0,0 +1,360 @@
+// Signed distance functions for generating octrees. Distances are negative inside a shape.
+//
+// Every function here is 1-Lipschitz: it never changes faster than the distance to the point
+// it's sampled at. That's what makes `classifier` conservative, a box whose center is further
+// from the surface than the box reaches can't contain any of the surface. Some combinators
+// only give a lower bound on the real distance, which keeps that property.
+
+use glam::*;
+
+use crate::octree::{is_leaf_octant, Color, OctantFillState, VoxelOctree};
+
+pub trait Sdf {
+    /// Signed distance from `p` to the surface, negative inside. It may underestimate the
+    /// distance, but never overestimate it.
+    fn distance(&self, p: Vec3) -> f32;
+
+    fn union<S: Sdf>(self, other: S) -> Union<Self, S>
+    where
+        Self: Sized,
+    {
+        Union { a: self, b: other }
+    }
+
+    fn intersection<S: Sdf>(self, other: S) -> Intersection<Self, S>
+    where
+        Self: Sized,
+    {
+        Intersection { a: self, b: other }
+    }
+
+    /// Removes `other` from this shape.
+    fn difference<S: Sdf>(self, other: S) -> Difference<Self, S>
+    where
+        Self: Sized,
+    {
+        Difference { a: self, b: other }
+    }
+
+    /// Union that blends the shapes together where they are closer than `k` to each other.
+    fn smooth_union<S: Sdf>(self, other: S, k: f32) -> SmoothUnion<Self, S>
+    where
+        Self: Sized,
+    {
+        SmoothUnion { a: self, b: other, k }
+    }
+
+    fn translate(self, offset: Vec3) -> Translate<Self>
+    where
+        Self: Sized,
+    {
+        Translate { sdf: self, offset }
+    }
+
+    fn rotate(self, rotation: Quat) -> Rotate<Self>
+    where
+        Self: Sized,
+    {
+        Rotate { sdf: self, inverse: rotation.inverse() }
+    }
+
+    fn scale(self, factor: f32) -> Scale<Self>
+    where
+        Self: Sized,
+    {
+        Scale { sdf: self, factor }
+    }
+}
+
+impl<S: Sdf + ?Sized> Sdf for &S {
+    fn distance(&self, p: Vec3) -> f32 {
+        (**self).distance(p)
+    }
+}
+
+impl<S: Sdf + ?Sized> Sdf for Box<S> {
+    fn distance(&self, p: Vec3) -> f32 {
+        (**self).distance(p)
+    }
+}
+
+/// Turns an SDF into a classifier for `VoxelOctree::generate`, with `leaf_size` the voxel
+/// size at max depth. An octant is empty or full when its center is further from the surface
+/// than half its diagonal, and gets subdivided otherwise. Voxels at max depth are solid when
+/// their center is inside the shape.
+pub fn classifier<S: Sdf>(sdf: &S, color: Color, leaf_size: Vec3) -> impl Fn(Vec3, Vec3, Vec3) -> OctantFillState + Copy + '_ {
+    move |center, inner, outer| {
+        let distance = sdf.distance(center);
+        if is_leaf_octant(inner, outer, leaf_size) {
+            return if distance < 0.0 { OctantFillState::Full(color) } else { OctantFillState::Empty };
+        }
+
+        let reach = (outer - inner).length() * 0.5;
+        if distance > reach {
+            OctantFillState::Empty
+        } else if distance < -reach {
+            OctantFillState::Full(color)
+        } else {
+            OctantFillState::ContainsVoxel(color)
+        }
+    }
+}
+
+impl VoxelOctree {
+    /// Fills the tree with the inside of `sdf` down to voxels of `max_depth`, see
+    /// `sdf::classifier`.
+    pub fn generate_sdf<S: Sdf>(&mut self, sdf: &S, max_depth: u8, color: Color) -> usize {
+        let leaf_size = self.leaf_size(max_depth);
+        self.generate(max_depth, classifier(sdf, color, leaf_size))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sphere {
+    pub radius: f32,
+}
+
+impl Sdf for Sphere {
+    fn distance(&self, p: Vec3) -> f32 {
+        p.length() - self.radius
+    }
+}
+
+/// Axis aligned box around the origin.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cuboid {
+    pub half_size: Vec3,
+}
+
+impl Sdf for Cuboid {
+    fn distance(&self, p: Vec3) -> f32 {
+        let q = p.abs() - self.half_size;
+        q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+    }
+}
+
+/// Axis aligned box around the origin with its edges rounded off by `radius`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoundedBox {
+    pub half_size: Vec3,
+    pub radius: f32,
+}
+
+impl Sdf for RoundedBox {
+    fn distance(&self, p: Vec3) -> f32 {
+        let inner = Cuboid { half_size: self.half_size - Vec3::splat(self.radius) };
+        inner.distance(p) - self.radius
+    }
+}
+
+/// Torus around the y axis.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Torus {
+    /// Distance from the origin to the center of the tube.
+    pub major_radius: f32,
+    /// Radius of the tube.
+    pub minor_radius: f32,
+}
+
+impl Sdf for Torus {
+    fn distance(&self, p: Vec3) -> f32 {
+        let q = vec2(vec2(p.x, p.z).length() - self.major_radius, p.y);
+        q.length() - self.minor_radius
+    }
+}
+
+/// Capped cylinder along the y axis, centered on the origin.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cylinder {
+    pub radius: f32,
+    pub half_height: f32,
+}
+
+impl Sdf for Cylinder {
+    fn distance(&self, p: Vec3) -> f32 {
+        let d = vec2(vec2(p.x, p.z).length() - self.radius, p.y.abs() - self.half_height);
+        d.max_element().min(0.0) + d.max(Vec2::ZERO).length()
+    }
+}
+
+/// Line segment from `a` to `b` with a radius.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capsule {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub radius: f32,
+}
+
+impl Sdf for Capsule {
+    fn distance(&self, p: Vec3) -> f32 {
+        let pa = p - self.a;
+        let ba = self.b - self.a;
+        let h = (pa.dot(ba) / ba.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+        (pa - ba * h).length() - self.radius
+    }
+}
+
+/// Capped cone along the y axis, centered on the origin. A `top_radius` of 0 gives a
+/// pointed cone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cone {
+    pub half_height: f32,
+    pub bottom_radius: f32,
+    pub top_radius: f32,
+}
+
+impl Sdf for Cone {
+    fn distance(&self, p: Vec3) -> f32 {
+        let (h, r1, r2) = (self.half_height, self.bottom_radius, self.top_radius);
+        let q = vec2(vec2(p.x, p.z).length(), p.y);
+        let k1 = vec2(r2, h);
+        let k2 = vec2(r2 - r1, 2.0 * h);
+        //Closest point on the caps and on the slanted side, in the plane through the axis
+        let cap = vec2(q.x - q.x.min(if q.y < 0.0 { r1 } else { r2 }), q.y.abs() - h);
+        let side = q - k1 + k2 * ((k1 - q).dot(k2) / k2.length_squared()).clamp(0.0, 1.0);
+        let sign = if side.x < 0.0 && cap.y < 0.0 { -1.0 } else { 1.0 };
+        sign * cap.length_squared().min(side.length_squared()).sqrt()
+    }
+}
+
+/// Half space below the plane `dot(p, normal) == offset`. `normal` has to be normalized.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plane {
+    pub normal: Vec3,
+    pub offset: f32,
+}
+
+impl Sdf for Plane {
+    fn distance(&self, p: Vec3) -> f32 {
+        p.dot(self.normal) - self.offset
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Union<A, B> {
+    pub a: A,
+    pub b: B,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Union<A, B> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.a.distance(p).min(self.b.distance(p))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Intersection<A, B> {
+    pub a: A,
+    pub b: B,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Intersection<A, B> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.a.distance(p).max(self.b.distance(p))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Difference<A, B> {
+    pub a: A,
+    pub b: B,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Difference<A, B> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.a.distance(p).max(-self.b.distance(p))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SmoothUnion<A, B> {
+    pub a: A,
+    pub b: B,
+    pub k: f32,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for SmoothUnion<A, B> {
+    fn distance(&self, p: Vec3) -> f32 {
+        let (a, b) = (self.a.distance(p), self.b.distance(p));
+        if self.k <= 0.0 {
+            return a.min(b);
+        }
+        //Quadratic smooth minimum, its gradient is a blend of the two gradients so it stays 1-Lipschitz
+        let h = (self.k - (a - b).abs()).max(0.0) / self.k;
+        a.min(b) - h * h * self.k * 0.25
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Translate<S> {
+    pub sdf: S,
+    pub offset: Vec3,
+}
+
+impl<S: Sdf> Sdf for Translate<S> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.sdf.distance(p - self.offset)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotate<S> {
+    pub sdf: S,
+    //Stored inverted, since sampling rotates the point the other way
+    inverse: Quat,
+}
+
+impl<S: Sdf> Sdf for Rotate<S> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.sdf.distance(self.inverse * p)
+    }
+}
+
+/// Uniform scale around the origin. Non uniform scaling would break the distance bound.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scale<S> {
+    pub sdf: S,
+    pub factor: f32,
+}
+
+impl<S: Sdf> Sdf for Scale<S> {
+    fn distance(&self, p: Vec3) -> f32 {
+        self.sdf.distance(p / self.factor) * self.factor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Every unit voxel of a 16 unit tree around the origin
+    fn voxels() -> impl Iterator<Item = Vec3> {
+        (0..16 * 16 * 16).map(|i| vec3((i / 256) as f32, (i / 16 % 16) as f32, (i % 16) as f32) - Vec3::splat(7.5))
+    }
+
+    fn generated<S: Sdf>(sdf: &S) -> VoxelOctree {
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        tree.generate_sdf(sdf, 4, (1, 2, 3));
+        tree
+    }
+
+    #[test]
+    fn aligned_box_is_exact() {
+        let tree = generated(&Cuboid { half_size: vec3(4.0, 2.0, 3.0) });
+        let volume: f32 = tree.leaves().map(|(_, bounds)| (bounds.half_size * 2.0).x.powi(3)).sum();
+        assert_eq!(volume, 8.0 * 4.0 * 6.0);
+        for pos in voxels() {
+            let inside = pos.x.abs() < 4.0 && pos.y.abs() < 2.0 && pos.z.abs() < 3.0;
+            assert_eq!(tree.get_voxel(pos).is_some(), inside, "voxel at {}", pos);
+        }
+    }
+
+    #[test]
+    fn voxels_sample_their_center() {
+        let sdf = Sphere { radius: 4.0 }.union(Torus { major_radius: 5.0, minor_radius: 1.3 }.translate(vec3(0.3, 1.0, -0.2)));
+        let tree = generated(&sdf);
+        for pos in voxels() {
+            assert_eq!(tree.get_voxel(pos).is_some(), sdf.distance(pos) < 0.0, "voxel at {}", pos);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+// Binary octree format, all values little endian:
+//
+//   [ 0- 7] magic bytes "ICEVOXOT"
+//   [ 8-11] u32 format version, currently 1
+//   [12-23] 3x f32 root center
+//   [24-35] 3x f32 root half size
+//   [36-43] u64 node count
+//   [44-..] node stream
+//   [last 4] u32 CRC-32 (IEEE) of everything before it
+//
+// The node stream is the tree in depth-first pre-order, starting at the root. Every node
+// starts with a u8 tag: 0 for an empty node, 1 for a leaf and 2 for an interior node.
+// A leaf is followed by its colour as 3 u8s (r, g, b). An interior node is followed by
+// a u8 child mask and then the nodes of the children in that mask, in octant index order.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use glam::*;
+
+use crate::octree::{Octant, VoxelOctree};
+
+pub const MAGIC: &[u8; 8] = b"ICEVOXOT";
+pub const VERSION: u32 = 1;
+
+const TAG_EMPTY: u8 = 0;
+const TAG_LEAF: u8 = 1;
+const TAG_INTERIOR: u8 = 2;
+
+//Deeper than this can't be represented with f32 bounds anyway, so it's treated as corrupt
+//input rather than risking a stack overflow while loading
+const MAX_DEPTH: u8 = 64;
+
+#[derive(Debug)]
+pub enum OctreeIoError {
+    Io(io::Error),
+    /// The input ended before the whole tree was read.
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u32),
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// The input is structurally invalid, with a description of what was wrong.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for OctreeIoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OctreeIoError::Io(e) => write!(f, "io error: {}", e),
+            OctreeIoError::Truncated => write!(f, "unexpected end of octree data"),
+            OctreeIoError::BadMagic => write!(f, "not an octree file"),
+            OctreeIoError::UnsupportedVersion(v) => write!(f, "unsupported octree format version {}", v),
+            OctreeIoError::ChecksumMismatch { expected, actual } => write!(f, "checksum mismatch, expected {:08x} but got {:08x}", expected, actual),
+            OctreeIoError::Corrupt(reason) => write!(f, "corrupt octree data: {}", reason),
+        }
+    }
+}
+
+impl Error for OctreeIoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OctreeIoError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for OctreeIoError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => OctreeIoError::Truncated,
+            _ => OctreeIoError::Io(e),
+        }
+    }
+}
+
+//Bitwise CRC-32 with the IEEE polynomial, same as zlib and PNG
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u32;
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+//Wraps a reader or writer so everything passing through is checksummed
+struct Checksummed<T> {
+    inner: T,
+    crc: Crc32,
+}
+
+impl<R: Read> Checksummed<R> {
+    fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N], OctreeIoError> {
+        let mut buf = [0u8; N];
+        self.inner.read_exact(&mut buf)?;
+        self.crc.update(&buf);
+        Ok(buf)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, OctreeIoError> {
+        Ok(self.read_bytes::<1>()?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, OctreeIoError> {
+        Ok(u32::from_le_bytes(self.read_bytes()?))
+    }
+
+    fn read_vec3(&mut self) -> Result<Vec3, OctreeIoError> {
+        let x = f32::from_le_bytes(self.read_bytes()?);
+        let y = f32::from_le_bytes(self.read_bytes()?);
+        let z = f32::from_le_bytes(self.read_bytes()?);
+        Ok(vec3(x,y,z))
+    }
+}
+
+impl<W: Write> Checksummed<W> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.crc.update(bytes);
+        self.inner.write_all(bytes)
+    }
+
+    fn write_vec3(&mut self, v: Vec3) -> io::Result<()> {
+        self.write_bytes(&v.x.to_le_bytes())?;
+        self.write_bytes(&v.y.to_le_bytes())?;
+        self.write_bytes(&v.z.to_le_bytes())
+    }
+}
+
+fn count_nodes(octant: &Octant) -> u64 {
+    1 + octant.children().map(|(_, c)| count_nodes(c)).sum::<u64>()
+}
+
+fn write_octant<W: Write>(w: &mut Checksummed<W>, octant: &Octant) -> io::Result<()> {
+    if octant.is_leaf() {
+        let (r, g, b) = octant.color();
+        w.write_bytes(&[TAG_LEAF, r, g, b])
+    } else if octant.child_mask() == 0 {
+        w.write_bytes(&[TAG_EMPTY])
+    } else {
+        w.write_bytes(&[TAG_INTERIOR, octant.child_mask()])?;
+        for (_, child) in octant.children() {
+            write_octant(w, child)?;
+        }
+        Ok(())
+    }
+}
+
+fn read_octant<R: Read>(r: &mut Checksummed<R>, depth: u8, nodes_left: &mut u64) -> Result<Octant, OctreeIoError> {
+    if *nodes_left == 0 {
+        return Err(OctreeIoError::Corrupt("more nodes than the header says"));
+    }
+    *nodes_left -= 1;
+
+    match r.read_u8()? {
+        TAG_EMPTY => Ok(Octant::empty()),
+        TAG_LEAF => {
+            let [red, green, blue] = r.read_bytes::<3>()?;
+            Ok(Octant::leaf(red, green, blue))
+        },
+        TAG_INTERIOR => {
+            if depth >= MAX_DEPTH {
+                return Err(OctreeIoError::Corrupt("tree is too deep"));
+            }
+            let mask = r.read_u8()?;
+            if mask == 0 {
+                return Err(OctreeIoError::Corrupt("interior node without children"));
+            }
+            let mut octant = Octant::empty();
+            for i in 0..8 {
+                if mask & (1 << i) != 0 {
+                    let child = read_octant(r, depth + 1, nodes_left)?;
+                    octant.set_child(i, Some(child));
+                }
+            }
+            //Interior colours aren't stored, they follow from the leaves
+            octant.update_lod();
+            Ok(octant)
+        },
+        _ => Err(OctreeIoError::Corrupt("unknown node tag")),
+    }
+}
+
+impl VoxelOctree {
+    /// Writes the tree in the binary format described at the top of `serialize.rs`.
+    pub fn save<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut w = Checksummed { inner: writer, crc: Crc32::new() };
+        w.write_bytes(MAGIC)?;
+        w.write_bytes(&VERSION.to_le_bytes())?;
+        w.write_vec3(self.center)?;
+        w.write_vec3(self.half_size)?;
+        w.write_bytes(&count_nodes(&self.root).to_le_bytes())?;
+        write_octant(&mut w, &self.root)?;
+
+        let checksum = w.crc.finish();
+        w.inner.write_all(&checksum.to_le_bytes())?;
+        w.inner.flush()
+    }
+
+    /// Reads a tree written by `save`, validating it along the way.
+    pub fn load<R: Read>(reader: R) -> Result<Self, OctreeIoError> {
+        let mut r = Checksummed { inner: reader, crc: Crc32::new() };
+        if &r.read_bytes::<8>()? != MAGIC {
+            return Err(OctreeIoError::BadMagic);
+        }
+        let version = r.read_u32()?;
+        if version != VERSION {
+            return Err(OctreeIoError::UnsupportedVersion(version));
+        }
+        let center = r.read_vec3()?;
+        let half_size = r.read_vec3()?;
+        if !center.is_finite() || !half_size.is_finite() || half_size.min_element() <= 0.0 {
+            return Err(OctreeIoError::Corrupt("invalid root bounds"));
+        }
+        let mut nodes_left = u64::from_le_bytes(r.read_bytes()?);
+        let root = read_octant(&mut r, 0, &mut nodes_left)?;
+        if nodes_left != 0 {
+            return Err(OctreeIoError::Corrupt("fewer nodes than the header says"));
+        }
+
+        let actual = r.crc.finish();
+        let mut expected = [0u8; 4];
+        r.inner.read_exact(&mut expected)?;
+        let expected = u32::from_le_bytes(expected);
+        if expected != actual {
+            return Err(OctreeIoError::ChecksumMismatch { expected, actual });
+        }
+
+        Ok(Self {
+            root,
+            center,
+            half_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_tree() -> VoxelOctree {
+        let mut tree = VoxelOctree::empty(vec3(1.0, -2.0, 3.0), Vec3::splat(16.0));
+        tree.set_voxel(vec3(0.5, -1.5, 3.5), 4, (255, 0, 0));
+        tree.set_voxel(vec3(-6.0, 5.0, 9.0), 2, (0, 128, 255));
+        tree.set_voxel(vec3(8.0, -9.0, -4.0), 6, (10, 20, 30));
+        tree
+    }
+
+    //Every node in depth-first order, with its data and bounds
+    fn nodes(tree: &VoxelOctree) -> Vec<(u64, [f32; 3], u8)> {
+        let mut nodes = Vec::new();
+        tree.visit(|octant, bounds| {
+            nodes.push((octant.data, bounds.center.into(), bounds.depth));
+            true
+        });
+        nodes
+    }
+
+    fn saved(tree: &VoxelOctree) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        tree.save(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn crc32_matches_reference() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let tree = sample_tree();
+        let loaded = VoxelOctree::load(&saved(&tree)[..]).unwrap();
+        assert_eq!(loaded.center, tree.center);
+        assert_eq!(loaded.half_size, tree.half_size);
+        assert_eq!(nodes(&loaded), nodes(&tree));
+    }
+
+    #[test]
+    fn round_trip_empty() {
+        let tree = VoxelOctree::empty(Vec3::ZERO, Vec3::ONE);
+        let loaded = VoxelOctree::load(&saved(&tree)[..]).unwrap();
+        assert_eq!(nodes(&loaded), nodes(&tree));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = saved(&sample_tree());
+        for &len in &[0, 4, 8, 20, 40, 44, bytes.len() / 2, bytes.len() - 5, bytes.len() - 1] {
+            match VoxelOctree::load(&bytes[..len]) {
+                Err(OctreeIoError::Truncated) => {},
+                other => panic!("expected truncation at {} bytes, got {:?}", len, other.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut bytes = saved(&sample_tree());
+        bytes[0] = b'X';
+        assert!(matches!(VoxelOctree::load(&bytes[..]), Err(OctreeIoError::BadMagic)));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = saved(&sample_tree());
+        bytes[8..12].copy_from_slice(&7u32.to_le_bytes());
+        assert!(matches!(VoxelOctree::load(&bytes[..]), Err(OctreeIoError::UnsupportedVersion(7))));
+    }
+
+    #[test]
+    fn flipped_byte() {
+        let bytes = saved(&sample_tree());
+        //Every byte after the version, so the error comes from validation or the checksum
+        for i in 12..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[i] ^= 0x10;
+            assert!(VoxelOctree::load(&corrupt[..]).is_err(), "flipped byte {} was accepted", i);
+        }
+    }
+
+    #[test]
+    fn flipped_colour_fails_checksum() {
+        let bytes = saved(&sample_tree());
+        //The last leaf colour is right before the checksum
+        let mut corrupt = bytes.clone();
+        corrupt[bytes.len() - 5] ^= 0xFF;
+        assert!(matches!(VoxelOctree::load(&corrupt[..]), Err(OctreeIoError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn corrupt_node_tag() {
+        let mut bytes = saved(&sample_tree());
+        bytes[44] = 9;
+        let len = bytes.len();
+        let checksum = {
+            let mut crc = Crc32::new();
+            crc.update(&bytes[..len - 4]);
+            crc.finish()
+        };
+        bytes[len - 4..].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(VoxelOctree::load(&bytes[..]), Err(OctreeIoError::Corrupt(_))));
+    }
+}
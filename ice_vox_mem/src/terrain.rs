@@ -0,0 +1,145 @@
+// Heightmap terrain from fractal noise.
+//
+// The surface height is `base_height + amplitude * fbm(x, z)`. Since the noise has a known
+// Lipschitz bound, the height anywhere above an octant is within a fixed distance of the
+// height at its center, so octants well above or below that range are decided without
+// subdividing them.
+
+use glam::*;
+
+use crate::noise::{Fbm, FbmParams};
+use crate::octree::{is_leaf_octant, Color, OctantFillState, VoxelOctree};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerrainParams {
+    pub noise: FbmParams,
+    /// Surface height where the noise is 0.
+    pub base_height: f32,
+    /// How far the noise moves the surface up and down.
+    pub amplitude: f32,
+    /// Surface above this height is covered in snow.
+    pub snow_height: f32,
+    /// Surface steeper than this, as height change per unit of distance, is bare rock.
+    pub rock_slope: f32,
+    /// How far the dirt goes down below the surface before it turns to rock.
+    pub dirt_depth: f32,
+    pub grass: Color,
+    pub dirt: Color,
+    pub rock: Color,
+    pub snow: Color,
+}
+
+impl Default for TerrainParams {
+    fn default() -> Self {
+        Self {
+            noise: FbmParams::default(),
+            base_height: 0.0,
+            amplitude: 32.0,
+            snow_height: 14.0,
+            rock_slope: 1.0,
+            dirt_depth: 3.0,
+            grass: (86, 150, 54),
+            dirt: (121, 85, 58),
+            rock: (118, 118, 118),
+            snow: (240, 244, 250),
+        }
+    }
+}
+
+pub struct Terrain {
+    pub params: TerrainParams,
+    fbm: Fbm,
+    lipschitz: f32,
+    slope_step: f32,
+}
+
+impl Terrain {
+    pub fn new(params: TerrainParams) -> Self {
+        let fbm = Fbm::new(params.noise);
+        let lipschitz = fbm.lipschitz2() * params.amplitude.abs();
+        //Small compared to the finest noise layer
+        let n = params.noise;
+        let finest_frequency = n.frequency * n.lacunarity.powi(n.octaves.max(1) as i32 - 1);
+        Self {
+            params,
+            fbm,
+            lipschitz,
+            slope_step: 0.1 / finest_frequency.max(f32::EPSILON),
+        }
+    }
+
+    pub fn height(&self, x: f32, z: f32) -> f32 {
+        self.params.base_height + self.params.amplitude * self.fbm.sample2(x, z)
+    }
+
+    /// Steepness of the surface, as height change per unit of distance.
+    pub fn slope(&self, x: f32, z: f32) -> f32 {
+        let e = self.slope_step;
+        let dx = self.height(x + e, z) - self.height(x - e, z);
+        let dz = self.height(x, z + e) - self.height(x, z - e);
+        vec2(dx, dz).length() / (2.0 * e)
+    }
+
+    /// Lowest and highest the surface gets within `radius` of `(x, z)`.
+    pub fn height_bounds(&self, x: f32, z: f32, radius: f32) -> (f32, f32) {
+        let h = self.height(x, z);
+        let spread = self.params.amplitude.abs();
+        let (lowest, highest) = (self.params.base_height - spread, self.params.base_height + spread);
+        ((h - self.lipschitz * radius).max(lowest), (h + self.lipschitz * radius).min(highest))
+    }
+
+    /// Colour of the ground at `pos`, given the surface height above it and the thickness of
+    /// the top layer.
+    pub fn color(&self, pos: Vec3, surface: f32, layer: f32) -> Color {
+        let depth = surface - pos.y;
+        let p = &self.params;
+        if depth > p.dirt_depth {
+            return p.rock;
+        }
+        if self.slope(pos.x, pos.z) > p.rock_slope {
+            p.rock
+        } else if depth > layer {
+            p.dirt
+        } else if surface > p.snow_height {
+            p.snow
+        } else {
+            p.grass
+        }
+    }
+
+    /// Classifier for `VoxelOctree::generate`, with `leaf_size` the voxel size at max depth.
+    pub fn classifier(&self, leaf_size: Vec3) -> impl Fn(Vec3, Vec3, Vec3) -> OctantFillState + Copy + '_ {
+        move |center, inner, outer| {
+            if is_leaf_octant(inner, outer, leaf_size) {
+                let surface = self.height(center.x, center.z);
+                return if center.y < surface {
+                    OctantFillState::Full(self.color(center, surface, leaf_size.y))
+                } else {
+                    OctantFillState::Empty
+                };
+            }
+
+            let half_size = (outer - inner).abs() * 0.5;
+            let (lowest, highest) = self.height_bounds(center.x, center.z, vec2(half_size.x, half_size.z).length());
+            if center.y - half_size.y >= highest {
+                OctantFillState::Empty
+            } else if center.y + half_size.y < lowest - self.params.dirt_depth {
+                //Deep enough that all of it is rock
+                OctantFillState::Full(self.params.rock)
+            } else {
+                OctantFillState::ContainsVoxel(self.params.rock)
+            }
+        }
+    }
+}
+
+impl VoxelOctree {
+    /// Fills the tree with noise terrain down to voxels of `max_depth`.
+    pub fn generate_terrain(&mut self, params: TerrainParams, max_depth: u8) -> usize {
+        let terrain = Terrain::new(params);
+        let leaf_size = self.leaf_size(max_depth);
+        let nodes_generated = self.generate(max_depth, terrain.classifier(leaf_size));
+        trace!("Terrain nodes generated: {}", nodes_generated);
+        nodes_generated
+    }
+}
@@ -0,0 +1,852 @@
+// MagicaVoxel .vox support, see https://github.com/ephtracy/voxel-model for the format.
+//
+// Models keep MagicaVoxel's coordinate system (z up) and are placed in world space using
+// the translations from the scene graph when there is one. Model rotations are ignored.
+// Every voxel becomes a cube of 1x1x1 units.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use glam::*;
+
+use crate::octree::{Color, NodeBounds, VoxelOctree};
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io(io::Error),
+    /// The input ended in the middle of a chunk.
+    Truncated,
+    BadMagic,
+    /// The input is structurally invalid, with a description of what was wrong.
+    Corrupt(&'static str),
+    /// The requested export doesn't fit in a .vox file, or the imported scene is too big to
+    /// place its voxels exactly.
+    TooLarge,
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoxError::Io(e) => write!(f, "io error: {}", e),
+            VoxError::Truncated => write!(f, "unexpected end of .vox data"),
+            VoxError::BadMagic => write!(f, "not a .vox file"),
+            VoxError::Corrupt(reason) => write!(f, "corrupt .vox data: {}", reason),
+            VoxError::TooLarge => write!(f, "too many voxels for a .vox file"),
+        }
+    }
+}
+
+impl Error for VoxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VoxError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VoxError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => VoxError::Truncated,
+            _ => VoxError::Io(e),
+        }
+    }
+}
+
+/// The palette MagicaVoxel uses when a file has no RGBA chunk, indexed by colour index.
+pub fn default_palette() -> [Color; 256] {
+    const STEPS: [u8; 6] = [0xFF, 0xCC, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xEE, 0xDD, 0xBB, 0xAA, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut palette = [(0, 0, 0); 256];
+    let mut i = 1;
+    //A 6x6x6 colour cube without black, followed by blue, green, red and grey ramps
+    for &r in STEPS.iter() {
+        for &g in STEPS.iter() {
+            for &b in STEPS.iter() {
+                if (r, g, b) != (0, 0, 0) {
+                    palette[i] = (r, g, b);
+                    i += 1;
+                }
+            }
+        }
+    }
+    for &v in RAMP.iter() { palette[i] = (0, 0, v); i += 1; }
+    for &v in RAMP.iter() { palette[i] = (0, v, 0); i += 1; }
+    for &v in RAMP.iter() { palette[i] = (v, 0, 0); i += 1; }
+    for &v in RAMP.iter() { palette[i] = (v, v, v); i += 1; }
+    palette
+}
+
+pub struct VoxModel {
+    pub size: [u32; 3],
+    /// Voxel positions inside the model together with their colour index.
+    pub voxels: Vec<([u8; 3], u8)>,
+    /// World position of the model's voxel (0, 0, 0).
+    pub offset: IVec3,
+}
+
+pub struct VoxFile {
+    pub models: Vec<VoxModel>,
+    /// Colours by colour index, index 0 means empty and is unused.
+    pub palette: [Color; 256],
+}
+
+//Far deeper than MagicaVoxel nests, but shallow enough to recurse through safely
+const MAX_SCENE_DEPTH: usize = 1024;
+//Largest scene that still has exact voxel centers as f32
+const MAX_IMPORT_SIZE: i64 = 1 << 24;
+
+//Scene graph nodes, only what's needed to place the models
+enum SceneNode {
+    Transform { child: i32, translation: IVec3 },
+    Group { children: Vec<i32> },
+    Shape { models: Vec<i32> },
+}
+
+struct ChunkReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ChunkReader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], VoxError> {
+        if self.data.len() < n {
+            return Err(VoxError::Truncated);
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn i32(&mut self) -> Result<i32, VoxError> {
+        let b = self.bytes(4)?;
+        Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn len(&mut self) -> Result<usize, VoxError> {
+        let n = self.i32()?;
+        if n < 0 {
+            return Err(VoxError::Corrupt("negative length"));
+        }
+        Ok(n as usize)
+    }
+
+    fn string(&mut self) -> Result<String, VoxError> {
+        let n = self.len()?;
+        Ok(String::from_utf8_lossy(self.bytes(n)?).into_owned())
+    }
+
+    fn dict(&mut self) -> Result<HashMap<String, String>, VoxError> {
+        let n = self.len()?;
+        let mut dict = HashMap::new();
+        for _ in 0..n {
+            let key = self.string()?;
+            let value = self.string()?;
+            dict.insert(key, value);
+        }
+        Ok(dict)
+    }
+}
+
+//Translations come straight from the file, so adding them up can overflow
+fn checked_add(a: IVec3, b: IVec3) -> Result<IVec3, VoxError> {
+    let add = |a: i32, b: i32| a.checked_add(b).ok_or(VoxError::Corrupt("translation out of range"));
+    Ok(ivec3(add(a.x, b.x)?, add(a.y, b.y)?, add(a.z, b.z)?))
+}
+
+fn parse_translation(value: &str) -> Result<IVec3, VoxError> {
+    let parts: Vec<i32> = value.split_whitespace().map(|p| p.parse()).collect::<Result<_, _>>()
+        .map_err(|_| VoxError::Corrupt("invalid translation"))?;
+    match parts[..] {
+        [x, y, z] => Ok(ivec3(x,y,z)),
+        _ => Err(VoxError::Corrupt("invalid translation")),
+    }
+}
+
+impl VoxFile {
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, VoxError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let mut r = ChunkReader { data: &data };
+
+        if r.bytes(4)? != b"VOX " {
+            return Err(VoxError::BadMagic);
+        }
+        let _version = r.i32()?;
+
+        //MAIN has no content of its own, all other chunks are its children
+        if r.bytes(4)? != b"MAIN" {
+            return Err(VoxError::Corrupt("missing MAIN chunk"));
+        }
+        let main_content = r.len()?;
+        let main_children = r.len()?;
+        r.bytes(main_content)?;
+        let mut chunks = ChunkReader { data: r.bytes(main_children)? };
+
+        let mut file = VoxFile {
+            models: Vec::new(),
+            palette: default_palette(),
+        };
+        let mut sizes: Vec<[u32; 3]> = Vec::new();
+        let mut nodes: HashMap<i32, SceneNode> = HashMap::new();
+
+        while !chunks.data.is_empty() {
+            let id = chunks.bytes(4)?;
+            let content_len = chunks.len()?;
+            let children_len = chunks.len()?;
+            let mut c = ChunkReader { data: chunks.bytes(content_len)? };
+            chunks.bytes(children_len)?;
+
+            match id {
+                b"SIZE" => {
+                    let x = c.len()?;
+                    let y = c.len()?;
+                    let z = c.len()?;
+                    sizes.push([x as u32, y as u32, z as u32]);
+                },
+                b"XYZI" => {
+                    let size = *sizes.get(file.models.len()).ok_or(VoxError::Corrupt("XYZI chunk without SIZE"))?;
+                    let n = c.len()?;
+                    let mut voxels = Vec::with_capacity(n.min(c.data.len() / 4));
+                    for _ in 0..n {
+                        let v = c.bytes(4)?;
+                        if v[0] as u32 >= size[0] || v[1] as u32 >= size[1] || v[2] as u32 >= size[2] {
+                            return Err(VoxError::Corrupt("voxel outside of model"));
+                        }
+                        if v[3] != 0 {
+                            voxels.push(([v[0], v[1], v[2]], v[3]));
+                        }
+                    }
+                    file.models.push(VoxModel {
+                        size,
+                        voxels,
+                        offset: IVec3::ZERO,
+                    });
+                },
+                b"RGBA" => {
+                    //Palette entry i is used by colour index i + 1, the last entry is unused
+                    for i in 0..255 {
+                        let rgba = c.bytes(4)?;
+                        file.palette[i + 1] = (rgba[0], rgba[1], rgba[2]);
+                    }
+                },
+                b"nTRN" => {
+                    let id = c.i32()?;
+                    let _attributes = c.dict()?;
+                    let child = c.i32()?;
+                    let _reserved = c.i32()?;
+                    let _layer = c.i32()?;
+                    let frames = c.len()?;
+                    let mut translation = IVec3::ZERO;
+                    //Only the first frame matters, animations aren't supported
+                    if frames > 0 {
+                        if let Some(t) = c.dict()?.get("_t") {
+                            translation = parse_translation(t)?;
+                        }
+                    }
+                    nodes.insert(id, SceneNode::Transform { child, translation });
+                },
+                b"nGRP" => {
+                    let id = c.i32()?;
+                    let _attributes = c.dict()?;
+                    let n = c.len()?;
+                    let children = (0..n).map(|_| c.i32()).collect::<Result<_, _>>()?;
+                    nodes.insert(id, SceneNode::Group { children });
+                },
+                b"nSHP" => {
+                    let id = c.i32()?;
+                    let _attributes = c.dict()?;
+                    let n = c.len()?;
+                    let mut models = Vec::new();
+                    for _ in 0..n {
+                        models.push(c.i32()?);
+                        let _model_attributes = c.dict()?;
+                    }
+                    nodes.insert(id, SceneNode::Shape { models });
+                },
+                //PACK, MATL, LAYR, rOBJ, rCAM, NOTE, IMAP and anything newer
+                _ => {},
+            }
+        }
+
+        if !nodes.is_empty() {
+            let mut placed = vec![false; file.models.len()];
+            let mut visited = HashSet::new();
+            file.place_models(&nodes, 0, IVec3::ZERO, &mut placed, &mut visited, 0)?;
+        }
+
+        //Voxel centers have to be exact in f32 once they're in the octree
+        let mut min = [i64::MAX; 3];
+        let mut max = [i64::MIN; 3];
+        for model in file.models.iter() {
+            for i in 0..3 {
+                min[i] = min[i].min(model.offset[i] as i64);
+                max[i] = max[i].max(model.offset[i] as i64 + model.size[i] as i64);
+            }
+        }
+        if (0..3).any(|i| max[i] - min[i] > MAX_IMPORT_SIZE) {
+            return Err(VoxError::TooLarge);
+        }
+
+        Ok(file)
+    }
+
+    //Walks the scene graph from `node`, accumulating translations down to the shapes. Every
+    //node has a single parent, so reaching one twice means the graph is broken
+    fn place_models(&mut self, nodes: &HashMap<i32, SceneNode>, node: i32, translation: IVec3, placed: &mut [bool], visited: &mut HashSet<i32>, depth: usize) -> Result<(), VoxError> {
+        if !visited.insert(node) {
+            return Err(VoxError::Corrupt("scene node reached more than once"));
+        }
+        if depth > MAX_SCENE_DEPTH {
+            return Err(VoxError::Corrupt("scene graph too deep"));
+        }
+        match nodes.get(&node) {
+            Some(SceneNode::Transform { child, translation: t }) => {
+                self.place_models(nodes, *child, checked_add(translation, *t)?, placed, visited, depth + 1)?;
+            },
+            Some(SceneNode::Group { children }) => {
+                for child in children {
+                    self.place_models(nodes, *child, translation, placed, visited, depth + 1)?;
+                }
+            },
+            Some(SceneNode::Shape { models }) => {
+                for &model in models {
+                    let index = model as usize;
+                    if model < 0 || index >= self.models.len() {
+                        return Err(VoxError::Corrupt("shape refers to a missing model"));
+                    }
+                    //Only the first instance of a model is used
+                    if !placed[index] {
+                        //MagicaVoxel translates the center of the model, rounding down
+                        let size = self.models[index].size;
+                        let half = ivec3(size[0] as i32 / 2, size[1] as i32 / 2, size[2] as i32 / 2);
+                        let offset = checked_add(translation, -half)?;
+                        //The far corner has to fit as well
+                        checked_add(offset, ivec3(size[0] as i32, size[1] as i32, size[2] as i32))?;
+                        self.models[index].offset = offset;
+                        placed[index] = true;
+                    }
+                }
+            },
+            None => return Err(VoxError::Corrupt("missing scene node")),
+        }
+        Ok(())
+    }
+
+    /// Builds a single octree containing all models at their scene positions.
+    pub fn to_octree(&self) -> VoxelOctree {
+        let voxels = self.models.iter().flat_map(|model| {
+            model.voxels.iter().map(move |&([x, y, z], color_index)| {
+                (model.offset + ivec3(x as i32, y as i32, z as i32), color_index)
+            })
+        });
+        voxels_to_octree(voxels, &self.palette)
+    }
+}
+
+impl VoxModel {
+    /// Builds an octree from just this model, ignoring its scene position.
+    pub fn to_octree(&self, palette: &[Color; 256]) -> VoxelOctree {
+        let voxels = self.voxels.iter().map(|&([x, y, z], color_index)| (ivec3(x as i32, y as i32, z as i32), color_index));
+        voxels_to_octree(voxels, palette)
+    }
+}
+
+//Picks the smallest power of two cube around the voxels, so every voxel is a leaf at max depth
+fn voxels_to_octree<I>(voxels: I, palette: &[Color; 256]) -> VoxelOctree
+where
+    I: Iterator<Item = (IVec3, u8)> + Clone
+{
+    let mut min = IVec3::splat(i32::MAX);
+    let mut max = IVec3::splat(i32::MIN);
+    for (pos, _) in voxels.clone() {
+        min = min.min(pos);
+        max = max.max(pos);
+    }
+    if min.x > max.x {
+        return VoxelOctree::empty(Vec3::ZERO, Vec3::ONE);
+    }
+
+    //In i64, the corners can be further apart than an i32 reaches
+    let extent = (0..3).map(|i| max[i] as i64 - min[i] as i64 + 1).max().unwrap() as u64;
+    let depth = 64 - (extent - 1).leading_zeros();
+    let size = (1u64 << depth) as f32;
+    let origin = min.as_f32();
+    let mut tree = VoxelOctree::empty(origin + Vec3::splat(size / 2.0), Vec3::splat(size));
+
+    for (pos, color_index) in voxels {
+        tree.set_voxel(pos.as_f32() + Vec3::splat(0.5), depth as u8, palette[color_index as usize]);
+    }
+    trace!("Imported .vox with depth {}", depth);
+    tree
+}
+
+impl VoxelOctree {
+    /// Reads a .vox file and builds an octree from all the models in it.
+    pub fn import_vox<R: Read>(reader: R) -> Result<Self, VoxError> {
+        Ok(VoxFile::read(reader)?.to_octree())
+    }
+}
+
+//MagicaVoxel can't handle models bigger than this, larger exports are split up
+const MAX_MODEL_SIZE: u32 = 256;
+//Deepest export depth, keeps voxel coordinates well inside i32
+const MAX_EXPORT_DEPTH: u8 = 24;
+/// Most voxels a single export writes, every one of them is held in memory while exporting.
+pub const MAX_EXPORT_VOXELS: u64 = 1 << 24;
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(content.len() as i32).to_le_bytes());
+    out.extend_from_slice(&(children.len() as i32).to_le_bytes());
+    out.extend_from_slice(content);
+    out.extend_from_slice(children);
+}
+
+fn write_dict(out: &mut Vec<u8>, pairs: &[(&str, &str)]) {
+    out.extend_from_slice(&(pairs.len() as i32).to_le_bytes());
+    for (key, value) in pairs {
+        for s in [key, value].iter() {
+            out.extend_from_slice(&(s.len() as i32).to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+    }
+}
+
+fn write_i32s(out: &mut Vec<u8>, values: &[i32]) {
+    for v in values {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+fn color_distance(a: Color, b: Color) -> u32 {
+    let dr = a.0 as i32 - b.0 as i32;
+    let dg = a.1 as i32 - b.1 as i32;
+    let db = a.2 as i32 - b.2 as i32;
+    (dr * dr + dg * dg + db * db) as u32
+}
+
+fn channel(c: Color, i: usize) -> u8 {
+    match i {
+        0 => c.0,
+        1 => c.1,
+        _ => c.2,
+    }
+}
+
+//Median cut: keep splitting the box with the widest channel range at its median,
+//then use the weighted average of every box as a palette entry
+fn quantize(colors: &HashMap<Color, usize>, max_colors: usize) -> Vec<Color> {
+    //Sorted, so the palette doesn't depend on the order of the hash map
+    let mut colors: Vec<(Color, usize)> = colors.iter().map(|(&c, &n)| (c, n)).collect();
+    colors.sort();
+    let mut boxes = vec![colors];
+
+    let range = |b: &Vec<(Color, usize)>, i: usize| {
+        let min = b.iter().map(|(c, _)| channel(*c, i)).min().unwrap_or(0);
+        let max = b.iter().map(|(c, _)| channel(*c, i)).max().unwrap_or(0);
+        max - min
+    };
+
+    while boxes.len() < max_colors {
+        let widest = (0..boxes.len())
+            .filter(|&b| boxes[b].len() > 1)
+            .map(|b| (b, (0..3).max_by_key(|&i| range(&boxes[b], i)).unwrap()))
+            .max_by_key(|&(b, i)| range(&boxes[b], i));
+        let (b, i) = match widest {
+            Some(widest) => widest,
+            None => break,
+        };
+
+        let mut split = boxes.swap_remove(b);
+        split.sort_by_key(|(c, _)| channel(*c, i));
+        let upper = split.split_off(split.len() / 2);
+        boxes.push(split);
+        boxes.push(upper);
+    }
+
+    boxes.iter().map(|b| {
+        let total: usize = b.iter().map(|(_, n)| n).sum();
+        let avg = |i: usize| (b.iter().map(|(c, n)| channel(*c, i) as usize * n).sum::<usize>() / total.max(1)) as u8;
+        (avg(0), avg(1), avg(2))
+    }).collect()
+}
+
+impl VoxelOctree {
+    /// Writes the whole tree to a .vox file with voxels the size of nodes at `depth`.
+    pub fn export_vox<W: Write>(&self, writer: W, depth: u8) -> Result<(), VoxError> {
+        self.export_vox_region(writer, depth, self.center - self.half_size, self.center + self.half_size)
+    }
+
+    /// Writes the voxels at `depth` intersecting the box between `min` and `max` to a .vox file.
+    /// Coarser leaves are expanded to that resolution, finer ones take up the voxel they're in.
+    /// If there are more than 255 distinct colours they get quantised to fit the palette, and
+    /// regions over 256 voxels wide are split into multiple models. Exports with more than
+    /// `MAX_EXPORT_VOXELS` solid voxels fail with `VoxError::TooLarge`.
+    pub fn export_vox_region<W: Write>(&self, mut writer: W, depth: u8, min: Vec3, max: Vec3) -> Result<(), VoxError> {
+        if depth > MAX_EXPORT_DEPTH {
+            return Err(VoxError::TooLarge);
+        }
+
+        let root = self.bounds();
+        let root_min = root.min();
+        let cells = 1i64 << depth;
+        let cell_size = self.half_size * 2.0 / cells as f32;
+
+        //Range of cells touched by the region, clamped to the tree
+        let lo = ((min - root_min) / cell_size).floor();
+        let hi = ((max - root_min) / cell_size).ceil();
+        let lo = [0, 1, 2].map(|i| (lo[i] as i64).max(0));
+        let hi = [0, 1, 2].map(|i| (hi[i] as i64).min(cells));
+
+        //Range of export cells covered by a node, clamped to the region
+        let covered = |bounds: NodeBounds| {
+            let size = self.half_size * 2.0 / (1i64 << bounds.depth) as f32;
+            let index = ((bounds.min() - root_min) / size).round();
+            let (node_lo, node_hi) = if bounds.depth <= depth {
+                let scale = 1i64 << (depth - bounds.depth);
+                let node_lo = [0, 1, 2].map(|i| index[i] as i64 * scale);
+                (node_lo, [0, 1, 2].map(|i| node_lo[i] + scale))
+            } else {
+                let shift = bounds.depth - depth;
+                let node_lo = [0, 1, 2].map(|i| (index[i] as i64) >> shift);
+                (node_lo, [0, 1, 2].map(|i| node_lo[i] + 1))
+            };
+            if (0..3).any(|i| node_hi[i] <= lo[i] || node_lo[i] >= hi[i]) {
+                None
+            } else {
+                Some(([0, 1, 2].map(|i| node_lo[i].max(lo[i])), [0, 1, 2].map(|i| node_hi[i].min(hi[i]))))
+            }
+        };
+
+        //Count before expanding anything, a coarse leaf can cover more cells than fit in memory
+        let mut total = 0u64;
+        //Nodes at the export depth fill a single cell, however many leaves they hold
+        self.visit(|octant, bounds| match covered(bounds) {
+            Some((from, to)) if octant.is_leaf() || (bounds.depth >= depth && octant.child_mask() != 0) => {
+                let volume = (0..3).fold(1u64, |v, i| v.saturating_mul((to[i] - from[i]) as u64));
+                total = total.saturating_add(volume);
+                false
+            },
+            Some(_) => total <= MAX_EXPORT_VOXELS,
+            None => false,
+        });
+        if total > MAX_EXPORT_VOXELS {
+            return Err(VoxError::TooLarge);
+        }
+
+        let mut voxels: HashMap<[i64; 3], Color> = HashMap::new();
+        self.visit(|octant, bounds| {
+            let (from, to) = match covered(bounds) {
+                Some(range) => range,
+                None => return false,
+            };
+            if octant.is_leaf() {
+                for x in from[0]..to[0] {
+                    for y in from[1]..to[1] {
+                        for z in from[2]..to[2] {
+                            voxels.entry([x, y, z]).or_insert_with(|| octant.color());
+                        }
+                    }
+                }
+            }
+            true
+        });
+
+        //Palette, index 0 means empty so there's room for 255 colours
+        let mut counts: HashMap<Color, usize> = HashMap::new();
+        for color in voxels.values() {
+            *counts.entry(*color).or_insert(0) += 1;
+        }
+        let palette: Vec<Color> = if counts.len() <= 255 {
+            //Most used first, so the output doesn't depend on the order of the hash map
+            let mut colors: Vec<(Color, usize)> = counts.iter().map(|(&c, &n)| (c, n)).collect();
+            colors.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            colors.into_iter().map(|(c, _)| c).collect()
+        } else {
+            trace!("Quantising {} colours down to 255", counts.len());
+            quantize(&counts, 255)
+        };
+        let color_index: HashMap<Color, u8> = counts.keys().map(|&c| {
+            let nearest = (0..palette.len()).min_by_key(|&i| color_distance(palette[i], c)).unwrap();
+            (c, nearest as u8 + 1)
+        }).collect();
+
+        //Split the region into models of at most MAX_MODEL_SIZE on every axis
+        let mut models: HashMap<[i64; 3], Vec<[u8; 4]>> = HashMap::new();
+        let model_size = MAX_MODEL_SIZE as i64;
+        for (pos, color) in voxels.iter() {
+            let rel = [0, 1, 2].map(|i| pos[i] - lo[i]);
+            let key = rel.map(|v| v / model_size);
+            let local = rel.map(|v| (v % model_size) as u8);
+            models.entry(key).or_default().push([local[0], local[1], local[2], color_index[color]]);
+        }
+        for model in models.values_mut() {
+            model.sort();
+        }
+        let mut keys: Vec<[i64; 3]> = models.keys().cloned().collect();
+        keys.sort();
+
+        //Keep voxel coordinates in world units where the grid lines up with integers
+        let world_offset = (root_min / cell_size).round();
+
+        let mut body = Vec::new();
+        let mut scene = Vec::new();
+        let mut content = Vec::new();
+        write_i32s(&mut content, &[0]);
+        write_dict(&mut content, &[]);
+        write_i32s(&mut content, &[1, -1, 0, 1]);
+        write_dict(&mut content, &[]);
+        write_chunk(&mut scene, b"nTRN", &content, &[]);
+
+        content.clear();
+        write_i32s(&mut content, &[1]);
+        write_dict(&mut content, &[]);
+        write_i32s(&mut content, &[keys.len() as i32]);
+        write_i32s(&mut content, &(0..keys.len()).map(|m| 2 + m as i32 * 2).collect::<Vec<_>>());
+        write_chunk(&mut scene, b"nGRP", &content, &[]);
+
+        for (m, key) in keys.iter().enumerate() {
+            let model = &models[key];
+            let model_lo = [0, 1, 2].map(|i| lo[i] + key[i] * model_size);
+            let size = [0, 1, 2].map(|i| (hi[i] - model_lo[i]).min(model_size) as i32);
+
+            content.clear();
+            write_i32s(&mut content, &size);
+            write_chunk(&mut body, b"SIZE", &content, &[]);
+
+            content.clear();
+            write_i32s(&mut content, &[model.len() as i32]);
+            for v in model {
+                content.extend_from_slice(v);
+            }
+            write_chunk(&mut body, b"XYZI", &content, &[]);
+
+            //MagicaVoxel positions the center of the model, rounding down
+            let t = [0, 1, 2].map(|i| model_lo[i] + world_offset[i] as i64 + (size[i] / 2) as i64);
+            let t = format!("{} {} {}", t[0], t[1], t[2]);
+            content.clear();
+            write_i32s(&mut content, &[2 + m as i32 * 2]);
+            write_dict(&mut content, &[]);
+            write_i32s(&mut content, &[3 + m as i32 * 2, -1, 0, 1]);
+            write_dict(&mut content, &[("_t", &t)]);
+            write_chunk(&mut scene, b"nTRN", &content, &[]);
+
+            content.clear();
+            write_i32s(&mut content, &[3 + m as i32 * 2]);
+            write_dict(&mut content, &[]);
+            write_i32s(&mut content, &[1, m as i32]);
+            write_dict(&mut content, &[]);
+            write_chunk(&mut scene, b"nSHP", &content, &[]);
+        }
+        body.extend_from_slice(&scene);
+
+        //Entry i of the RGBA chunk is used by colour index i + 1
+        content.clear();
+        for i in 0..256 {
+            let (r, g, b) = palette.get(i).cloned().unwrap_or((0, 0, 0));
+            content.extend_from_slice(&[r, g, b, 255]);
+        }
+        write_chunk(&mut body, b"RGBA", &content, &[]);
+
+        let mut file = Vec::new();
+        file.extend_from_slice(b"VOX ");
+        write_i32s(&mut file, &[150]);
+        write_chunk(&mut file, b"MAIN", &[], &body);
+        writer.write_all(&file)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octree::Octant;
+
+    fn transform(id: i32, child: i32, t: Option<&str>) -> Vec<u8> {
+        let mut content = Vec::new();
+        write_i32s(&mut content, &[id]);
+        write_dict(&mut content, &[]);
+        write_i32s(&mut content, &[child, -1, 0, 1]);
+        match t {
+            Some(t) => write_dict(&mut content, &[("_t", t)]),
+            None => write_dict(&mut content, &[]),
+        }
+        let mut chunk = Vec::new();
+        write_chunk(&mut chunk, b"nTRN", &content, &[]);
+        chunk
+    }
+
+    fn group(id: i32, children: &[i32]) -> Vec<u8> {
+        let mut content = Vec::new();
+        write_i32s(&mut content, &[id]);
+        write_dict(&mut content, &[]);
+        write_i32s(&mut content, &[children.len() as i32]);
+        write_i32s(&mut content, children);
+        let mut chunk = Vec::new();
+        write_chunk(&mut chunk, b"nGRP", &content, &[]);
+        chunk
+    }
+
+    fn shape(id: i32, model: i32) -> Vec<u8> {
+        let mut content = Vec::new();
+        write_i32s(&mut content, &[id]);
+        write_dict(&mut content, &[]);
+        write_i32s(&mut content, &[1, model]);
+        write_dict(&mut content, &[]);
+        let mut chunk = Vec::new();
+        write_chunk(&mut chunk, b"nSHP", &content, &[]);
+        chunk
+    }
+
+    //A file with `models` single voxel models and the given scene graph chunks
+    fn file(models: usize, scene: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for _ in 0..models {
+            let mut content = Vec::new();
+            write_i32s(&mut content, &[1, 1, 1]);
+            write_chunk(&mut body, b"SIZE", &content, &[]);
+            content.clear();
+            write_i32s(&mut content, &[1]);
+            content.extend_from_slice(&[0, 0, 0, 1]);
+            write_chunk(&mut body, b"XYZI", &content, &[]);
+        }
+        for chunk in scene {
+            body.extend_from_slice(chunk);
+        }
+        let mut file = Vec::new();
+        file.extend_from_slice(b"VOX ");
+        write_i32s(&mut file, &[150]);
+        write_chunk(&mut file, b"MAIN", &[], &body);
+        file
+    }
+
+    //Two models translated to `a` and `b`
+    fn two_models(a: &str, b: &str) -> Vec<u8> {
+        file(2, &[
+            transform(0, 1, None),
+            group(1, &[2, 4]),
+            transform(2, 3, Some(a)),
+            shape(3, 0),
+            transform(4, 5, Some(b)),
+            shape(5, 1),
+        ])
+    }
+
+    #[test]
+    fn places_models() {
+        let file = VoxFile::read(&two_models("4 0 0", "-3 2 1")[..]).unwrap();
+        assert_eq!(file.models[0].offset, ivec3(4, 0, 0));
+        assert_eq!(file.models[1].offset, ivec3(-3, 2, 1));
+        let tree = file.to_octree();
+        assert!(tree.get_voxel(vec3(4.5, 0.5, 0.5)).is_some());
+        assert!(tree.get_voxel(vec3(-2.5, 2.5, 1.5)).is_some());
+    }
+
+    #[test]
+    fn translation_overflow() {
+        let bytes = two_models("2147483647 0 0", "-2147483648 0 0");
+        assert!(matches!(VoxFile::read(&bytes[..]), Err(VoxError::Corrupt(_))));
+        let bytes = file(1, &[transform(0, 1, Some("2147483647 0 0")), transform(1, 2, Some("1 0 0")), shape(2, 0)]);
+        assert!(matches!(VoxFile::read(&bytes[..]), Err(VoxError::Corrupt(_))));
+    }
+
+    #[test]
+    fn scene_too_large() {
+        let bytes = two_models("2000000000 0 0", "-2000000000 0 0");
+        assert!(matches!(VoxFile::read(&bytes[..]), Err(VoxError::TooLarge)));
+    }
+
+    #[test]
+    fn scene_cycle() {
+        let bytes = file(1, &[transform(0, 1, None), group(1, &[0])]);
+        assert!(matches!(VoxFile::read(&bytes[..]), Err(VoxError::Corrupt(_))));
+    }
+
+    #[test]
+    fn shared_scene_nodes() {
+        //Every group lists the next one twice, which would take 2^40 steps to walk
+        let mut scene = vec![transform(0, 1, None)];
+        for i in 1..=40 {
+            scene.push(group(i, &[i + 1, i + 1]));
+        }
+        scene.push(shape(41, 0));
+        let bytes = file(1, &scene);
+        assert!(matches!(VoxFile::read(&bytes[..]), Err(VoxError::Corrupt(_))));
+    }
+
+    #[test]
+    fn deep_scene() {
+        let mut scene: Vec<Vec<u8>> = (0..5000).map(|i| transform(i, i + 1, None)).collect();
+        scene.push(shape(5000, 0));
+        let bytes = file(1, &scene);
+        assert!(matches!(VoxFile::read(&bytes[..]), Err(VoxError::Corrupt(_))));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = two_models("4 0 0", "-3 2 1");
+        for len in (0..bytes.len()).step_by(7) {
+            assert!(VoxFile::read(&bytes[..len]).is_err(), "accepted {} of {} bytes", len, bytes.len());
+        }
+    }
+
+    fn exported(tree: &VoxelOctree, depth: u8) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        tree.export_vox(&mut bytes, depth).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(16.0));
+        for i in 0..40 {
+            let pos = vec3((i * 7 % 16) as f32 - 7.5, (i * 5 % 16) as f32 - 7.5, (i * 3 % 16) as f32 - 7.5);
+            tree.set_voxel(pos, 4, ((i * 6) as u8, 255 - i as u8, 7));
+        }
+        tree.set_voxel(vec3(4.0, 4.0, 4.0), 2, (1, 2, 3));
+
+        let imported = VoxelOctree::import_vox(&exported(&tree, 4)[..]).unwrap();
+        for x in -8..8 {
+            for y in -8..8 {
+                for z in -8..8 {
+                    let pos = vec3(x as f32, y as f32, z as f32) + Vec3::splat(0.5);
+                    let expected = tree.get_voxel(pos).map(|v| v.color);
+                    assert_eq!(imported.get_voxel(pos).map(|v| v.color), expected, "voxel at {}", pos);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn export_is_deterministic() {
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(32.0));
+        //More colours than fit in the palette, so quantisation runs too
+        for i in 0..600u32 {
+            let pos = vec3((i % 32) as f32 - 15.5, ((i / 32) % 32) as f32 - 15.5, (i % 7) as f32);
+            tree.set_voxel(pos, 5, ((i * 37) as u8, (i * 11) as u8, (i / 3) as u8));
+        }
+        assert_eq!(exported(&tree, 5), exported(&tree, 5));
+
+        let mut few = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(8.0));
+        for i in 0..50u32 {
+            few.set_voxel(vec3((i % 8) as f32 - 3.5, (i / 8) as f32 - 3.5, 0.5), 3, ((i % 9) as u8, 0, 0));
+        }
+        assert_eq!(exported(&few, 3), exported(&few, 3));
+    }
+
+    #[test]
+    fn export_too_large() {
+        let mut tree = VoxelOctree::empty(Vec3::ZERO, Vec3::splat(512.0));
+        tree.root = Octant::leaf(1, 2, 3);
+        let mut bytes = Vec::new();
+        assert!(matches!(tree.export_vox(&mut bytes, 9), Err(VoxError::TooLarge)));
+        assert!(matches!(tree.export_vox(&mut bytes, 30), Err(VoxError::TooLarge)));
+        //A small region of the same tree is fine
+        tree.export_vox_region(&mut bytes, 9, Vec3::ZERO, Vec3::splat(16.0)).unwrap();
+    }
+}